@@ -0,0 +1,85 @@
+//! `--old-archive`で渡された投稿時のアーカイブ(`.zip`、`.tar.gz`、`.tgz`、`.tar`)を展開する。
+
+use std::{
+    collections::VecDeque,
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest as _, Sha256};
+
+use crate::error::{self, Error};
+
+/// 展開先の名前に使う、アーカイブの中身のSHA-256の先頭16桁。
+/// 同じ場所で差し替えられたアーカイブは別の値になり、中身が同じなら前回展開したものを使い回せる
+pub fn digest(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)[..8].iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// 拡張子で形式を判断して`dest`に展開する
+pub fn unpack(path: &Path, bytes: &[u8], dest: &Path) -> error::Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy().to_lowercase();
+    let failed = |message: String| Error::ParseFailed { path: path.to_owned(), message };
+    if name.ends_with(".zip") {
+        let mut zip = zip::ZipArchive::new(Cursor::new(bytes)).map_err(|e| failed(e.to_string()))?;
+        zip.extract(dest).map_err(|e| failed(e.to_string()))
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        tar::Archive::new(flate2::read::GzDecoder::new(bytes)).unpack(dest).map_err(|e| failed(e.to_string()))
+    } else if name.ends_with(".tar") {
+        tar::Archive::new(bytes).unpack(dest).map_err(|e| failed(e.to_string()))
+    } else {
+        Err(failed("unknown archive format (expected .zip, .tar.gz, .tgz or .tar)".to_string()))
+    }
+}
+
+/// 展開したツリーからメインの`.tex`を探す。同じ名前のものが無ければ`\documentclass`のあるものにする。
+/// アーカイブには最上位にフォルダが一つあることが多いので、浅いものを優先する
+pub fn find_main(tree: &Path, tex_name: &Path) -> Option<PathBuf> {
+    let mut queue = VecDeque::from([tree.to_path_buf()]);
+    let mut fallback = None;
+    while let Some(dir) = queue.pop_front() {
+        let mut entries: Vec<PathBuf> = std::fs::read_dir(&dir).ok()?.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+        entries.sort();
+        for path in entries {
+            if path.is_dir() {
+                queue.push_back(path);
+            } else if path.file_name() == Some(tex_name.as_os_str()) {
+                return Some(path);
+            } else if fallback.is_none()
+                && path.extension().is_some_and(|ext| ext == "tex")
+                && std::fs::read(&path).is_ok_and(|src| String::from_utf8_lossy(&src).contains("\\documentclass"))
+            {
+                fallback = Some(path);
+            }
+        }
+    }
+    fallback
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn unpacks_and_finds_the_main_file() {
+        let dir = TempDir::new("archive");
+        let mut tar = tar::Builder::new(Vec::new());
+        for (name, src) in [("paper/main.tex", "\\documentclass{article}"), ("paper/sec/a.tex", "a")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(src.len() as u64);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, name, src.as_bytes()).unwrap();
+        }
+        let archive = dir.join("submission.tar");
+        std::fs::write(&archive, tar.into_inner().unwrap()).unwrap();
+
+        let bytes = std::fs::read(&archive).unwrap();
+        assert_eq!(digest(&bytes).len(), 16);
+        assert_ne!(digest(&bytes), digest(&bytes[1..]));
+        unpack(&archive, &bytes, &dir.join("tree")).unwrap();
+        assert_eq!(find_main(&dir.join("tree"), Path::new("main.tex")), Some(dir.join("tree/paper/main.tex")));
+        // 名前が違っても`\documentclass`のあるものを選ぶ
+        assert_eq!(find_main(&dir.join("tree"), Path::new("other.tex")), Some(dir.join("tree/paper/main.tex")));
+    }
+}
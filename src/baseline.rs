@@ -0,0 +1,361 @@
+//! 差分の比較元(古い側)のリビジョンにあるファイルを、バージョン管理システム(`vcs::Backend`)から取り出す。
+//! 取り出したものはリビジョンのハッシュごとに`tmpdir/old-<hash>`へ置き、次回以降はそのまま使う。
+//! `--old-dir`などで手元のファイルが比較元の場合は、そのディレクトリをそのまま使う。
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    cmd_for_diff, error, osstr_join,
+    archive,
+    param::{Against, OldSource, Param},
+    tex::{self, Flattened, aux::Aux},
+    vcs::{self, Backend, git::Git},
+};
+
+/// `--async-diff`の子と親のように、同じ比較元を同時に展開したりビルドしたりしないためのロックファイル
+const LOCK_NAME: &str = "lock";
+
+/// 拡張子の無い画像に付けて探す拡張子。graphicxのpdfTeX用ドライバと同じ順(大文字のものは区別せずに探す)
+const GRAPHICS_EXTS: &[&str] = &["png", "pdf", "jpg", "mps", "jpeg", "jbig2", "jb2", "eps"];
+
+pub struct Baseline<'a> {
+    pub param: &'a Param,
+    source: Source<'a>,
+    /// 文書ディレクトリからの相対パスでのメインの`.tex`。`--old-file`では名前が違うことがある
+    main: PathBuf,
+    pub root: PathBuf,
+}
+
+enum Source<'a> {
+    /// バージョン管理下のリビジョン(またはgitのインデックスの木)
+    Vcs {
+        vcs: Box<dyn Backend + 'a>,
+        id: String,
+        prefix: PathBuf, // リポジトリのルートから文書ディレクトリまで
+    },
+    /// 手元にある古い版の文書ディレクトリ
+    Dir(PathBuf),
+}
+
+/// 古い側から取り出したファイル
+#[derive(Debug, Default)]
+pub struct Assets {
+    /// 文書中に書かれた画像のパス(`\includegraphics{fig/a}`の`fig/a`)から、取り出した先の絶対パスへ
+    pub graphics: HashMap<String, PathBuf>,
+}
+
+impl<'a> Baseline<'a> {
+    /// gitのリビジョンか手元のファイルと比較している場合に限り、その古い側を返す。
+    pub fn from_param(param: &'a Param) -> error::Result<Option<Baseline<'a>>> {
+        if let Some(old) = &param.old_source {
+            return Baseline::from_old_source(param, old).map(Some);
+        }
+        let Some(vcs) = vcs::backend(param)? else { return Ok(None) };
+        match param.against {
+            Some(Against::Index) => Baseline::index(param).map(Some),
+            _ => Baseline::resolve(param, vcs, param.old_revision()).map(Some),
+        }
+    }
+    /// `-r A -r B`の`B`。latexdiffを直接使う場合に、作業ツリーの代わりに新しい側として取り出す
+    pub fn new_side(param: &'a Param) -> error::Result<Option<Baseline<'a>>> {
+        let Some(rev) = param.new_revision() else { return Ok(None) };
+        let vcs = vcs::backend(param)?.unwrap_or_else(|| Box::new(Git { dir: &param.dir }));
+        Baseline::resolve(param, vcs, rev).map(Some)
+    }
+    /// gitのリビジョン`rev`の側。取り出し先は解決したハッシュで決まるので、同じリビジョンなら誰が作っても共有される。
+    /// `--series`などが`git rev-list`で集めたハッシュに使うので、`--hg`や`--jj`があってもgitで読む
+    pub fn at(param: &'a Param, rev: &str) -> error::Result<Baseline<'a>> {
+        Baseline::resolve(param, Box::new(Git { dir: &param.dir }), rev)
+    }
+    fn resolve(param: &'a Param, vcs: Box<dyn Backend + 'a>, rev: &str) -> error::Result<Baseline<'a>> {
+        let id = vcs.resolve(rev)?;
+        Baseline::new(param, vcs, id)
+    }
+    /// gitのインデックス(ステージした内容)の側。コミットの代わりに`git write-tree`の木を使う
+    pub fn index(param: &'a Param) -> error::Result<Baseline<'a>> {
+        let git = Git { dir: &param.dir };
+        let id = git.write_tree()?;
+        Baseline::new(param, Box::new(git), id)
+    }
+    fn new(param: &'a Param, vcs: Box<dyn Backend + 'a>, id: String) -> error::Result<Baseline<'a>> {
+        let prefix = vcs.prefix()?;
+        let root = param.tmpdir.join(format!("old-{}", &id[..id.len().min(12)]));
+        let main = PathBuf::from(osstr_join(&param.docfile, ".tex"));
+        Ok(Baseline { param, source: Source::Vcs { vcs, id, prefix }, main, root })
+    }
+    /// `--old-file`、`--old-dir`、`--old-archive`の側
+    fn from_old_source(param: &'a Param, old: &OldSource) -> error::Result<Baseline<'a>> {
+        let tex_name = PathBuf::from(osstr_join(&param.docfile, ".tex"));
+        let (dir, main, root) = match old {
+            OldSource::File(file) => {
+                let dir = file.parent().map_or_else(PathBuf::new, Path::to_path_buf);
+                (dir, file.file_name().map_or(tex_name, PathBuf::from), param.tmpdir.join("old-file"))
+            }
+            OldSource::Dir(dir) => (dir.clone(), tex_name, param.tmpdir.join("old-dir")),
+            OldSource::Archive(path) => {
+                // 同じアーカイブは一度だけ展開する
+                let bytes = error::read(path)?;
+                let root = param.tmpdir.join(format!("old-archive-{}", archive::digest(&bytes)));
+                let tree = root.join("tree");
+                error::create_dir_all(&root)?;
+                let _lock = error::lock(root.join(LOCK_NAME))?;
+                if !tree.exists() {
+                    let partial = root.join("tree.partial");
+                    error::remove_dir_all(&partial)?;
+                    error::create_dir_all(&partial)?;
+                    archive::unpack(path, &bytes, &partial)?;
+                    error::rename(&partial, &tree)?;
+                }
+                let main = archive::find_main(&tree, &tex_name)
+                    .ok_or_else(|| error::Error::NotInArchive { archive: path.clone(), name: tex_name.clone() })?;
+                let dir = main.parent().unwrap_or(&tree).to_path_buf();
+                (dir, main.file_name().map_or(tex_name, PathBuf::from), root)
+            }
+        };
+        Ok(Baseline { param, source: Source::Dir(dir), main, root })
+    }
+    /// リビジョンの説明(コミットメッセージの1行目など)。手元のファイルには無い
+    pub fn describe(&self) -> Option<String> {
+        match &self.source {
+            Source::Vcs { vcs, id, .. } => vcs.describe(id).ok(),
+            Source::Dir(_) => None,
+        }
+    }
+    /// 比較元から変わった文書ディレクトリ内のファイル(文書ディレクトリからの相対パス)。手元のファイルでは分からない
+    pub fn changed_files(&self) -> error::Result<Option<Vec<PathBuf>>> {
+        let Source::Vcs { vcs, id, prefix } = &self.source else { return Ok(None) };
+        let files = vcs.changed_files(id)?;
+        Ok(Some(files.iter().filter_map(|file| file.strip_prefix(prefix).ok()).map(Path::to_path_buf).collect()))
+    }
+    /// キャッシュなどの名前に使う、比較元ごとに異なる名前
+    pub fn name(&self) -> String {
+        self.root.file_name().unwrap_or_default().to_string_lossy().into_owned()
+    }
+
+    /// 文書ディレクトリからの相対パスでリビジョン時点のファイルを読む
+    pub fn read(&self, path: &Path) -> Option<Vec<u8>> {
+        match &self.source {
+            Source::Vcs { vcs, id, prefix } => vcs.show(id, &tex::normalize(&prefix.join(path))).ok(),
+            Source::Dir(dir) => std::fs::read(dir.join(path)).ok(),
+        }
+    }
+    pub fn flatten(&self) -> Flattened {
+        tex::flatten(&self.main, &mut |path| self.read(path).map(|bytes| String::from_utf8_lossy(&bytes).into_owned()))
+    }
+
+    pub fn assets_dir(&self) -> PathBuf {
+        self.root.join("assets")
+    }
+
+    /// `root`の中を作ったり消したりする間、同じ比較元を使うほかのプロセスを待たせる
+    fn lock(&self) -> error::Result<std::fs::File> {
+        error::create_dir_all(&self.root)?;
+        error::lock(self.root.join(LOCK_NAME))
+    }
+
+    /// 古い側の文書が参照している画像・PDF・`.bib`・`.sty`・`.cls`を`assets_dir`に取り出す。
+    pub fn extract_assets(&self) -> error::Result<Assets> {
+        let _lock = self.lock()?;
+        let files: HashSet<PathBuf> = match &self.source {
+            Source::Vcs { vcs, id, .. } => vcs.ls_files(id)?.into_iter().collect(),
+            Source::Dir(_) => HashSet::new(),
+        };
+        let src = self.flatten().text();
+        let mut assets = Assets::default();
+        let extract = |reference: &Path| -> error::Result<Option<PathBuf>> {
+            let (vcs, id, prefix) = match &self.source {
+                Source::Vcs { vcs, id, prefix } => (vcs, id, prefix),
+                // 手元のファイルは取り出すまでもない
+                Source::Dir(dir) => return Ok(Some(dir.join(reference)).filter(|path| path.is_file())),
+            };
+            let in_repo = tex::normalize(&prefix.join(reference));
+            if !files.contains(&in_repo) {
+                return Ok(None);
+            }
+            let dest = self.assets_dir().join(&in_repo);
+            if !dest.exists() {
+                // ハッシュごとのディレクトリなので、一度取り出したものは変わらない
+                error::create_dir_all(dest.parent().unwrap())?;
+                error::write(&dest, vcs.show(id, &in_repo)?)?;
+            }
+            Ok(Some(dest))
+        };
+
+        // 文書ディレクトリからの相対パスのディレクトリにあるファイルの名前
+        let list_dir = |dir: &Path| -> Vec<String> {
+            match &self.source {
+                Source::Vcs { prefix, .. } => {
+                    let dir = tex::normalize(&prefix.join(dir));
+                    let in_dir = files.iter().filter(|file| file.parent() == Some(dir.as_path()));
+                    in_dir.filter_map(|file| file.file_name()).map(|name| name.to_string_lossy().into_owned()).collect()
+                }
+                Source::Dir(root) => std::fs::read_dir(root.join(dir))
+                    .map(|entries| entries.filter_map(Result::ok).map(|entry| entry.file_name().to_string_lossy().into_owned()).collect())
+                    .unwrap_or_default(),
+            }
+        };
+        for (reference, path) in find_graphics(&src, &list_dir) {
+            if let Some(dest) = extract(&path)? {
+                assets.graphics.insert(reference, dest);
+            }
+        }
+
+        for bib in tex::bib_files(&src) {
+            extract(Path::new(&bib))?;
+        }
+        for cmd in tex::commands(&src, &["usepackage", "RequirePackage"], 1) {
+            for name in tex::split_list(cmd.args[0]) {
+                extract(Path::new(&tex::with_ext(name, "sty")))?;
+            }
+        }
+        for cmd in tex::commands(&src, &["documentclass"], 1) {
+            extract(Path::new(&tex::with_ext(cmd.args[0].trim(), "cls")))?;
+        }
+        Ok(assets)
+    }
+
+    pub fn tree_dir(&self) -> PathBuf {
+        self.root.join("tree")
+    }
+    pub fn build_dir(&self) -> PathBuf {
+        self.root.join("build")
+    }
+
+    /// リビジョンのツリー全体を`tree_dir`に展開し、その中の文書ディレクトリを返す。
+    pub fn extract_tree(&self) -> error::Result<PathBuf> {
+        if let Source::Dir(dir) = &self.source {
+            return Ok(dir.clone());
+        }
+        let _lock = self.lock()?;
+        self.extract_tree_locked()
+    }
+    /// `lock`を持ったまま`extract_tree`する
+    fn extract_tree_locked(&self) -> error::Result<PathBuf> {
+        let (vcs, id, prefix) = match &self.source {
+            Source::Vcs { vcs, id, prefix } => (vcs, id, prefix),
+            Source::Dir(dir) => return Ok(dir.clone()),
+        };
+        let tree = self.tree_dir();
+        if !tree.exists() {
+            // 途中で失敗したものを使い回さないよう、展開し終えてから名前を付ける
+            let partial = self.root.join("tree.partial");
+            error::remove_dir_all(&partial)?;
+            error::create_dir_all(&partial)?;
+            vcs.extract(id, &partial)?;
+            error::rename(&partial, &tree)?;
+        }
+        Ok(tree.join(prefix))
+    }
+    /// 比較に使うメインの`.tex`(展開した先の絶対パス)
+    pub fn main_tex(&self) -> error::Result<PathBuf> {
+        Ok(self.extract_tree()?.join(&self.main))
+    }
+
+    /// 古いリビジョンを現在と同じ設定でタイプセットする。成功したPDFがあれば再利用する。
+    pub fn build(&self) -> error::Result<Option<i32>> {
+        // ほかのプロセスが同じものをビルドしている間は待ち、終わればその結果を使う
+        let _lock = self.lock()?;
+        let build_dir = self.build_dir();
+        // エンジンなどを変えたら、別の設定で作ったPDFや補助ファイルは使わない
+        let stamp_path = build_dir.join("build-options");
+        let stamp = format!(
+            "{}\nlatexmk={}\njobname={}\nmain={}\npretex={}\n",
+            self.param.latexmk_opts.output_key(),
+            self.param.latexmk.display(),
+            self.param.docfile.to_string_lossy(),
+            self.main.display(),
+            self.param.pretex().unwrap_or_default()
+        );
+        let same_options = std::fs::read_to_string(&stamp_path).is_ok_and(|old| old == stamp);
+        // 手元のファイルは変わりうるので、判断はlatexmkに任せる
+        if same_options && matches!(self.source, Source::Vcs { .. }) && build_dir.join(osstr_join(&self.param.docfile, ".pdf")).exists() {
+            return Ok(Some(0));
+        }
+        if !same_options {
+            error::remove_dir_all(&build_dir)?;
+        }
+        let dir = self.extract_tree_locked()?;
+        let mut latexmk = self.param.latexmk_in(&dir, &build_dir);
+        let stem = self.main.with_extension("");
+        latexmk.docfile = stem.as_os_str();
+        let mut cmd = latexmk.command()?;
+        if latexmk.docfile != self.param.docfile {
+            // 出力は新しい版と同じ名前にそろえる
+            let mut jobname = OsString::from("-jobname=");
+            jobname.push(&self.param.docfile);
+            cmd.arg(jobname);
+        }
+        // 相対パスの\inputなどを古いツリーの中で解決させる
+        cmd.current_dir(&dir);
+        let code = cmd_for_diff(cmd, self.param.diff_only)?;
+        if code == Some(0) {
+            error::write(&stamp_path, stamp)?;
+        }
+        Ok(code)
+    }
+    /// `build`したPDFを差分PDFの隣に`<doc>-old.pdf`として置く
+    pub fn copy_pdf(&self) -> error::Result<()> {
+        let pdf = self.build_dir().join(osstr_join(&self.param.docfile, ".pdf"));
+        error::copy(pdf, self.param.diff_outdir().join(osstr_join(&self.param.docfile, "-old.pdf")))?;
+        Ok(())
+    }
+    /// `build`の結果の`.aux`と`.bbl`
+    pub fn aux(&self) -> error::Result<Aux> {
+        Aux::read(&self.build_dir(), &self.param.docfile.to_string_lossy())
+    }
+}
+
+/// 文書中の画像の参照から、`\graphicspath`と拡張子の省略をLaTeXと同じように考えて見つけたファイル(文書ディレクトリからの相対パス)へ。
+/// `list_dir`は文書ディレクトリからの相対パスのディレクトリにあるファイルの名前
+fn find_graphics(src: &str, list_dir: &dyn Fn(&Path) -> Vec<String>) -> Vec<(String, PathBuf)> {
+    let mut dirs = vec![String::new()];
+    for cmd in tex::commands(src, &["graphicspath"], 1) {
+        dirs.extend(cmd.args[0].split(['{', '}']).map(str::trim).filter(|d| !d.is_empty()).map(str::to_string));
+    }
+    let mut found = Vec::new();
+    for cmd in tex::commands(src, &["includegraphics", "includepdf"], 1) {
+        let reference = cmd.args[0].trim();
+        let path = dirs.iter().find_map(|dir| {
+            let path = PathBuf::from(format!("{dir}{reference}"));
+            let name = path.file_name()?.to_string_lossy().into_owned();
+            let names = list_dir(path.parent().unwrap_or(Path::new("")));
+            if Path::new(reference).extension().is_some() && names.contains(&name) {
+                return Some(path);
+            }
+            let with_ext = |ext: &str| names.iter().find(|n| n.strip_prefix(name.as_str()).and_then(|e| e.strip_prefix('.')).is_some_and(|e| e.eq_ignore_ascii_case(ext)));
+            GRAPHICS_EXTS.iter().find_map(|ext| with_ext(ext)).map(|n| path.with_file_name(n))
+        });
+        if let Some(path) = path {
+            found.push((reference.to_string(), path));
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_graphics_like_latex() {
+        let src = "\\graphicspath{{fig/}{../shared/}}\n\\includegraphics{a}\\includegraphics[width=1cm]{b}\\includegraphics{sub/c.pdf}\\includegraphics{d}\\includegraphics{gone}";
+        let list_dir = |dir: &Path| -> Vec<String> {
+            let names: &[&str] = match dir.to_str().unwrap() {
+                "fig" => &["a.pdf", "a.png", "b.PDF", "b.eps"],
+                "fig/sub" => &["c.pdf"],
+                "../shared" => &["d.jpg", "d.jpg.bak"],
+                "" => &["gone.txt"],
+                _ => &[],
+            };
+            names.iter().map(|n| n.to_string()).collect()
+        };
+        let graphics = find_graphics(src, &list_dir);
+        let graphics: Vec<(&str, &str)> = graphics.iter().map(|(r, p)| (r.as_str(), p.to_str().unwrap())).collect();
+        // pdfTeXのドライバはpngをpdfより先に探し、拡張子の大文字小文字は問わない
+        assert_eq!(graphics, [("a", "fig/a.png"), ("b", "fig/b.PDF"), ("sub/c.pdf", "fig/sub/c.pdf"), ("d", "../shared/d.jpg")]);
+    }
+}
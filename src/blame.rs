@@ -0,0 +1,216 @@
+//! 差分の追加部分に、その行を最後に変更したコミット(ハッシュ・著者・件名)を欄外の注かPDFの注釈として付ける。
+//! 比較元が何コミットも前のときに、どの変更が誰のどのコミットかを分かるようにする。
+//! 著者ごとに追加部分の色を変えることもできる。
+
+use std::{ops::Range, path::PathBuf};
+
+use crate::{
+    changes,
+    difftex::{DiffTex, HunkKind},
+    error, osstr_join,
+    param::Param,
+    tex,
+    vcs::git::{BlameLine, Git},
+};
+
+/// コミットの表示の仕方
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoteStyle {
+    /// `\marginpar`で欄外に書く
+    Margin,
+    /// pdfcommentパッケージの注釈にする(マウスを乗せると出る)
+    Tooltip,
+}
+
+/// 追加を含む変更ごとに、その行を最後に変更したコミット(範囲の中で一番新しいもの、未コミットのものが最優先)。
+/// gitの管理下に無ければ`None`
+fn hunk_commits(param: &Param, diff_tex: &DiffTex) -> Option<Vec<(Range<usize>, BlameLine)>> {
+    let git = Git { dir: &param.dir };
+    if !git.is_repo() {
+        return None;
+    }
+    let new = tex::flatten(&PathBuf::from(osstr_join(&param.docfile, ".tex")), &mut tex::read_from_dir(&param.dir));
+    let hunks = diff_tex.hunks();
+    let sources = changes::locate(diff_tex, &hunks, &new);
+    let mut commits = Vec::new();
+    for (hunk, source) in hunks.into_iter().zip(sources) {
+        if hunk.kind == HunkKind::Delete {
+            continue;
+        }
+        let Some((file, [lo, hi])) = source else { continue };
+        let lines = match git.blame(&file, lo, hi) {
+            Ok(lines) => lines,
+            // まだ`git add`していないファイルはblameできないので、未コミットとして扱う
+            Err(_) if git.is_untracked(&file) => Vec::new(),
+            Err(e) => {
+                eprintln!("Failed to blame {}:", file.display());
+                e.print();
+                continue;
+            }
+        };
+        let latest = lines.into_iter().max_by_key(|line| (!line.is_committed(), line.time));
+        commits.push((hunk.range, latest.unwrap_or_default()));
+    }
+    Some(commits)
+}
+
+/// `--blame-notes`と`--color-by-author`の分を差分`.tex`に書き込む。`mark_hunks`の後に呼ぶ。
+/// どちらも変更の位置を使うので、ずれないように一度にまとめて書き換える
+pub fn apply(param: &Param, diff_tex: &mut DiffTex) -> error::Result<()> {
+    let Some(commits) = hunk_commits(param, diff_tex) else { return Ok(()) };
+    let mut edits = Vec::new();
+    let mut preamble = Vec::new();
+    if let Some(style) = param.blame_notes {
+        notes(&commits, style, &mut edits, &mut preamble);
+    }
+    if param.color_by_author {
+        author_colors(&diff_tex.src, &commits, &mut edits, &mut preamble);
+    }
+    diff_tex.replace_all(edits);
+    diff_tex.add_preamble(&preamble.iter().map(String::as_str).collect::<Vec<_>>());
+    Ok(())
+}
+
+/// 追加を含む変更の前に`\DIFblame{hash}{author: subject}`を差し込む
+fn notes(commits: &[(Range<usize>, BlameLine)], style: NoteStyle, edits: &mut Vec<(Range<usize>, String)>, preamble: &mut Vec<String>) {
+    for (range, line) in commits {
+        let note = match line.is_committed() {
+            true => {
+                let what = format!("{}: {}", line.author, line.summary);
+                format!("\\DIFblame{{{}}}{{{}}}", &line.hash[..line.hash.len().min(7)], tex::escape(&what))
+            }
+            false => "\\DIFblame{uncommitted}{}".to_string(),
+        };
+        edits.push((range.start..range.start, note));
+    }
+    // 数式や図表の中では欄外に書けないので何もしない
+    preamble.extend(match style {
+        NoteStyle::Margin => vec![
+            "\\providecommand{\\DIFblame}[2]{\\ifmmode\\else\\ifinner\\else\\marginpar{\\raggedright\\tiny\\sffamily #1 #2}\\fi\\fi}".to_string(),
+        ],
+        NoteStyle::Tooltip => vec![
+            "\\usepackage{pdfcomment}".to_string(),
+            "\\providecommand{\\DIFblame}[2]{\\ifmmode\\else\\pdfcomment[icon=Note,author={#1}]{#2}\\fi}".to_string(),
+        ],
+    });
+}
+
+/// 著者ごとの色(Okabe-Itoの配色から、削除の赤と紛らわしいものを除いたもの)
+const AUTHOR_COLORS: &[&str] = &["0,0.45,0.70", "0,0.62,0.45", "0.90,0.60,0", "0.80,0.47,0.65", "0.34,0.71,0.91", "0.55,0.35,0.15", "0.45,0.45,0.45", "0.60,0.60,0"];
+
+/// 追加部分の`\DIFadd{..}`を`\DIFaddauA{..}`のような著者ごとのものに差し替え、1ページ目の上に凡例を付ける
+fn author_colors(src: &str, commits: &[(Range<usize>, BlameLine)], edits: &mut Vec<(Range<usize>, String)>, preamble: &mut Vec<String>) {
+    let mut authors: Vec<&str> = Vec::new();
+    for (range, line) in commits {
+        let author = if line.is_committed() { line.author.as_str() } else { "uncommitted" };
+        let n = authors.iter().position(|a| *a == author).unwrap_or_else(|| {
+            authors.push(author);
+            authors.len() - 1
+        });
+        for cmd in tex::commands(&src[range.clone()], &["DIFadd", "DIFaddFL"], 1) {
+            let name = range.start + cmd.start..range.start + cmd.start + 1 + cmd.name.len();
+            edits.push((name, format!("\\{}au{}", cmd.name, suffix(n))));
+        }
+    }
+    if authors.is_empty() {
+        return;
+    }
+    preamble.push("\\usepackage{color}".to_string());
+    let mut legend = Vec::new();
+    for (n, author) in authors.iter().enumerate() {
+        let (color, x) = (AUTHOR_COLORS[n % AUTHOR_COLORS.len()], suffix(n));
+        preamble.push(format!("\\definecolor{{DIFauthor{x}}}{{rgb}}{{{color}}}"));
+        for name in ["DIFadd", "DIFaddFL"] {
+            preamble.push(format!("\\providecommand{{\\{name}au{x}}}[1]{{{}}}", author_markup(src, name, &x)));
+        }
+        legend.push(format!("\\textcolor{{DIFauthor{x}}}{{\\rule{{0.8em}}{{0.8em}}}}~{}", tex::escape(author)));
+    }
+    // 本文の組版に影響しないよう、最初のページの上端に重ねて描く。フックの無い古いLaTeXではatbegshiを使う
+    let legend = format!("\\put(72,-30){{\\makebox[0pt][l]{{\\footnotesize Additions by: {}}}}}", legend.join("\\quad "));
+    preamble.push(format!(
+        "\\ifdefined\\AddToHookNext\\AddToHookNext{{shipout/foreground}}{{{legend}}}\\else\\usepackage{{atbegshi}}\\AtBeginShipoutNext{{\\AtBeginShipoutUpperLeftForeground{{{legend}}}}}\\fi"
+    ));
+}
+
+/// latexdiffが書いた`\DIFadd`(`\DIFaddFL`)の定義を、色だけ著者`x`のものにして写す。
+/// 本文中の`\color`には触れないよう、定義の中の色指定だけを差し替える
+fn author_markup(src: &str, name: &str, x: &str) -> String {
+    let head = format!("\\providecommand{{\\{name}}}[1]");
+    let body = src.find(&head).map(|at| at + head.len()).and_then(|open| {
+        let close = tex::group_end(src.as_bytes(), open, b'{', b'}')?;
+        Some(&src[open + 1..close])
+    });
+    let Some(body) = body else {
+        // 定義が見つからなければ外側で色を付ける(`\DIFadd`が自分で色を付けるなら効かない)
+        return format!("{{\\color{{DIFauthor{x}}}\\{name}{{#1}}}}");
+    };
+    let mut edits: Vec<(Range<usize>, String)> = tex::commands(body, &["color"], 1)
+        .into_iter()
+        .map(|cmd| (cmd.arg_ranges[0].clone(), format!("DIFauthor{x}")))
+        .chain(tex::commands(body, &["DIFadd"], 1).into_iter().map(|cmd| (cmd.start + 1..cmd.start + 1 + cmd.name.len(), format!("DIFaddau{x}"))))
+        .collect();
+    if edits.is_empty() {
+        return format!("{{\\color{{DIFauthor{x}}}{body}}}");
+    }
+    edits.sort_by_key(|(range, _)| range.start);
+    let mut markup = body.to_string();
+    for (range, text) in edits.into_iter().rev() {
+        markup.replace_range(range, &text);
+    }
+    markup
+}
+
+/// 0, 1, ..., 25, 26, ...を`A`, `B`, ..., `Z`, `AA`, ...にする(コマンド名に数字は使えない)
+fn suffix(mut n: usize) -> String {
+    let mut letters = Vec::new();
+    loop {
+        letters.push(b'A' + (n % 26) as u8);
+        if n < 26 {
+            break;
+        }
+        n = n / 26 - 1;
+    }
+    letters.reverse();
+    String::from_utf8(letters).expect("ascii")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_authors_with_letters() {
+        assert_eq!([0, 1, 25, 26, 27, 701, 702].map(suffix), ["A", "B", "Z", "AA", "AB", "ZZ", "AAA"]);
+    }
+
+    #[test]
+    fn writes_notes_before_hunks() {
+        let committed = BlameLine { hash: "1234567890abcdef".to_string(), author: "Alice".to_string(), time: 1, summary: "Fix 100% of typos".to_string() };
+        let uncommitted = BlameLine { hash: "0".repeat(40), ..Default::default() };
+        let commits = [(10..20, committed), (30..40, uncommitted)];
+        for style in [NoteStyle::Margin, NoteStyle::Tooltip] {
+            let (mut edits, mut preamble) = (Vec::new(), Vec::new());
+            notes(&commits, style, &mut edits, &mut preamble);
+            assert_eq!(edits, [(10..10, "\\DIFblame{1234567}{Alice: Fix 100\\% of typos}".to_string()), (30..30, "\\DIFblame{uncommitted}{}".to_string())]);
+            assert!(preamble.last().is_some_and(|p| p.starts_with("\\providecommand{\\DIFblame}[2]")));
+            assert_eq!(preamble.iter().any(|p| p.contains("pdfcomment}")), style == NoteStyle::Tooltip);
+        }
+    }
+
+    #[test]
+    fn colors_additions_by_author() {
+        let src = "\\providecommand{\\DIFadd}[1]{{\\protect\\color{blue}\\uwave{#1}}} %DIF PREAMBLE\n\
+                   \\providecommand{\\DIFaddFL}[1]{\\DIFadd{#1}} %DIF PREAMBLE\n\
+                   \\begin{document}\n\\DIFadd{one} \\DIFaddFL{two}\n\\end{document}\n";
+        let body = src.find("\\DIFadd{one}").unwrap();
+        let alice = BlameLine { hash: "1".repeat(40), author: "Alice".to_string(), ..Default::default() };
+        let (mut edits, mut preamble) = (Vec::new(), Vec::new());
+        author_colors(src, &[(body..src.len(), alice)], &mut edits, &mut preamble);
+        assert_eq!(edits, [(body..body + 7, "\\DIFaddauA".to_string()), (body + 13..body + 22, "\\DIFaddFLauA".to_string())]);
+        assert!(preamble.contains(&"\\providecommand{\\DIFaddauA}[1]{{\\protect\\color{DIFauthorA}\\uwave{#1}}}".to_string()));
+        assert!(preamble.contains(&"\\providecommand{\\DIFaddFLauA}[1]{\\DIFaddauA{#1}}".to_string()));
+        let legend = preamble.last().unwrap();
+        assert!(legend.contains("\\AddToHookNext{shipout/foreground}") && legend.contains("\\else\\usepackage{atbegshi}"));
+        assert_eq!(legend.matches("Additions by: \\textcolor{DIFauthorA}").count(), 2);
+    }
+}
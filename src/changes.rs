@@ -0,0 +1,294 @@
+//! 差分PDFのどこに変更が出たかを使う出力。
+//! ページは差分`.tex`に差し込んだ`\DIFhunk`が`.aux`に書いたものから取る(difftex::DiffTex::mark_hunks)。
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Range,
+    path::PathBuf,
+};
+
+use crate::{
+    baseline::Baseline,
+    cmd::latexmk::LaTeXMK,
+    cmd_for_diff,
+    difftex::{DiffTex, Heading, Hunk, HunkKind},
+    error::{self, Error},
+    osstr_join,
+    param::Param,
+    pdf,
+    tex::{self, Flattened, aux::Aux, synctex::SyncTex},
+};
+
+/// 変更のあるページだけを抜き出した`<doc>-diff-changes.pdf`を作る。先頭にはページの索引を付ける。
+pub fn changed_pages_pdf(param: &Param) -> error::Result<()> {
+    let diff_aux = Aux::read(&param.tmpdir, &param.diff_docfile.to_string_lossy())?;
+    let pages = changed_pages(&diff_aux);
+
+    let mut builder = pdf::Builder::new();
+    let forms = builder.import(&param.tmpdir.join(osstr_join(&param.diff_docfile, ".pdf")))?;
+    let Some(first) = forms.first() else { return Ok(()) };
+    let diff_name = param.diff_docfile.to_string_lossy();
+    let mut index = vec![format!("Pages with changes in {diff_name}.pdf"), String::new()];
+    if pages.is_empty() {
+        index.push("No changes.".to_string());
+    }
+    for (abs_page, (page, count)) in &pages {
+        index.push(format!("page {abs_page} (p. {page}): {count} change(s)"));
+    }
+    // 文書と同じプリアンブルでタイプセットすれば、日本語のファイル名なども文書と同じフォントで出せる
+    match index_pdf(param, &index) {
+        Ok(path) => {
+            for form in builder.import(&path)? {
+                builder.add_page(form.width, form.height, &[(form, 0.0, 0.0)]);
+            }
+        }
+        Err(e) => {
+            e.print();
+            eprintln!("Warning: typesetting the page index failed; it is written without non-ASCII characters");
+            builder.add_text_pages(first.width, first.height, &index);
+        }
+    }
+    for abs_page in pages.keys() {
+        // `\ReadonlyShipoutCounter`が無いときの代わりの`\c@page`は0のこともある
+        if let Some(form) = abs_page.checked_sub(1).and_then(|n| forms.get(n)) {
+            builder.add_page(form.width, form.height, &[(*form, 0.0, 0.0)]);
+        }
+    }
+    builder.save(&param.diff_outdir().join(osstr_join(&param.diff_docfile, "-changes.pdf")))
+}
+
+/// 変更のある物理ページ(1始まり)から、表示上のページ番号とそのページの変更の数へ
+fn changed_pages(diff_aux: &Aux) -> BTreeMap<usize, (&str, usize)> {
+    let mut pages = BTreeMap::<usize, (&str, usize)>::new();
+    for hunk in diff_aux.hunks.values() {
+        pages.entry(hunk.abs_page).or_insert((&hunk.page, 0)).1 += 1;
+    }
+    pages
+}
+
+/// 索引の行を差分`.tex`と同じプリアンブルの文書にしてタイプセットし、そのPDFを返す。一行目は見出しにする
+fn index_pdf(param: &Param, lines: &[String]) -> error::Result<PathBuf> {
+    let diff_tex = DiffTex::read(param.diff_tex_path())?;
+    let begin = tex::commands(&diff_tex.src, &["begin"], 1).into_iter().find(|cmd| cmd.args[0].trim() == "document");
+    let mut src = format!("{}\\begin{{document}}\n\\pagestyle{{empty}}\n", &diff_tex.src[..begin.map_or(0, |cmd| cmd.start)]);
+    for (n, line) in lines.iter().enumerate() {
+        src.push_str(&match (n, line.is_empty()) {
+            (0, _) => format!("{{\\noindent\\Large\\bfseries {}\\par}}\n", tex::escape(line)),
+            (_, true) => "\\medskip\n".to_string(),
+            _ => format!("\\noindent {}\\par\n", tex::escape(line)),
+        });
+    }
+    src.push_str("\\end{document}\n");
+    let docfile = osstr_join(&param.diff_docfile, "-index");
+    error::write(param.tmpdir.join(osstr_join(&docfile, ".tex")), src)?;
+    let latexmk = LaTeXMK {
+        latexmk: &param.latexmk,
+        dir: &param.tmpdir,
+        docfile: &docfile,
+        tmpdir: &param.tmpdir,
+        outdir: param.tmpdir.as_path().into(),
+        opts: &param.latexmk_opts,
+        pretex: None,
+    };
+    if cmd_for_diff(latexmk.command()?, param.diff_only)? != Some(0) {
+        return Err(Error::AlreadySaid);
+    }
+    Ok(param.tmpdir.join(osstr_join(&docfile, ".pdf")))
+}
+
+/// 変更一つ分の索引
+#[derive(Debug, serde::Serialize)]
+pub struct Change {
+    pub id: usize,
+    pub kind: HunkKind,
+    /// 新しい側のソース(文書ディレクトリからの相対パス)と行範囲。削除だけの場合は削除された位置
+    pub file: Option<PathBuf>,
+    pub lines: Option<[usize; 2]>,
+    pub section: Option<String>,
+    pub subsection: Option<String>,
+    /// 差分PDFでのページ(`\thepage`の表記と、先頭からの番号)
+    pub diff_page: Option<String>,
+    pub diff_abs_page: Option<usize>,
+    /// メインのPDFでのページ(SyncTeXがある場合のみ)
+    pub main_page: Option<usize>,
+    pub deleted: String,
+    pub added: String,
+}
+
+/// 差分`.tex`と両方のビルド結果から変更の一覧を作る。
+pub fn index(param: &Param) -> error::Result<Vec<Change>> {
+    let diff_tex = DiffTex::read(param.diff_tex_path())?;
+    let diff_aux = Aux::read(&param.tmpdir, &param.diff_docfile.to_string_lossy())?;
+    let new = tex::flatten(&PathBuf::from(osstr_join(&param.docfile, ".tex")), &mut tex::read_from_dir(&param.dir));
+    let synctex_path = param.tmpdir.join(osstr_join(&param.docfile, ".synctex.gz"));
+    let synctex = match synctex_path.exists() {
+        true => Some(SyncTex::read(&synctex_path, &std::env::current_dir().map_err(error::Error::CurrentDirFailed)?)?),
+        false => None,
+    };
+
+    let hunks = diff_tex.hunks();
+    let sources = locate(&diff_tex, &hunks, &new);
+    let headings = diff_tex.headings();
+    let mut changes = Vec::new();
+    for (n, (hunk, source)) in hunks.into_iter().zip(sources).enumerate() {
+        let (file, lines) = source.map_or((None, None), |(file, lines)| (Some(file), Some(lines)));
+        let main_page = match (&synctex, &file, lines) {
+            (Some(synctex), Some(file), Some([lo, _])) => synctex.page_of(&param.dir.join(file), lo),
+            _ => None,
+        };
+        let diff_page = hunk.ids.iter().find_map(|id| diff_aux.hunks.get(id));
+        let (section, subsection) = enclosing(&headings, hunk.range.start);
+        changes.push(Change {
+            id: n + 1,
+            kind: hunk.kind,
+            file,
+            lines,
+            section,
+            subsection,
+            diff_page: diff_page.map(|p| p.page.clone()),
+            diff_abs_page: diff_page.map(|p| p.abs_page),
+            main_page,
+            deleted: hunk.deleted,
+            added: hunk.added,
+        });
+    }
+    Ok(changes)
+}
+
+/// 各変更が新しい側のソース(`new`)のどのファイルの何行目から何行目に当たるか。
+/// 削除だけの変更は、その直前の対応の取れた行とする
+pub fn locate(diff_tex: &DiffTex, hunks: &[Hunk], new: &Flattened) -> Vec<Option<(PathBuf, [usize; 2])>> {
+    let line_map = map_lines(&diff_tex.new_side_lines(), new);
+    let line_of = |offset: usize| diff_tex.src[..offset].matches('\n').count();
+    hunks
+        .iter()
+        .map(|hunk| {
+            let (first, last) = (line_of(hunk.range.start), line_of(hunk.range.end));
+            let mapped: Vec<usize> = (first..=last).filter_map(|l| line_map.get(l).copied().flatten()).collect();
+            let mapped = match mapped.is_empty() {
+                true => line_map[..first.min(line_map.len())].iter().rev().find_map(|l| *l).into_iter().collect(),
+                false => mapped,
+            };
+            let head = &new.lines[*mapped.first()?];
+            let in_file = mapped.iter().map(|&l| &new.lines[l]).filter(|l| l.file == head.file).map(|l| l.line);
+            let (lo, hi) = in_file.fold((usize::MAX, 0), |(lo, hi), l| (lo.min(l), hi.max(l)));
+            Some((head.file.clone(), [lo, hi]))
+        })
+        .collect()
+}
+
+/// `<doc>-diff.changes.json`を差分PDFの隣に書く。
+pub fn write_index(param: &Param) -> error::Result<()> {
+    let changes = index(param)?;
+    let json = serde_json::to_vec_pretty(&changes).expect("plain data");
+    error::write(param.diff_outdir().join(osstr_join(&param.diff_docfile, ".changes.json")), json)
+}
+
+/// 差分`.tex`の`at`の位置を含む節と小節の見出し
+pub fn enclosing(headings: &[Heading], at: usize) -> (Option<String>, Option<String>) {
+    let (mut section, mut subsection) = (None, None);
+    for heading in headings.iter().take_while(|h| h.start <= at) {
+        match heading.level {
+            0..=2 => {
+                section = (heading.level == 2).then(|| heading.title.clone());
+                subsection = None;
+            }
+            3 => subsection = Some(heading.title.clone()),
+            _ => {}
+        }
+    }
+    (section, subsection)
+}
+
+/// 差分`.tex`の各行(新しい側に戻したもの)が、展開済みの新しいソースの何行目に当たるか。
+/// 同じ行は一番近い後ろのものを選び、見つからなければ近くでその行を含むものを探す(latexdiffが行を分けた場合)。
+/// latexdiffはマークアップの前後に空白を入れるので、空白を全て除いて比べる。
+fn map_lines(diff_lines: &[String], new: &Flattened) -> Vec<Option<usize>> {
+    let squash = |s: &str| s.split_whitespace().collect::<String>();
+    let keys: Vec<String> = new.lines.iter().map(|l| squash(tex::strip_comment(&l.text))).collect();
+    let mut positions = HashMap::<&str, Vec<usize>>::new();
+    for (j, key) in keys.iter().enumerate().filter(|(_, k)| !k.is_empty()) {
+        positions.entry(key).or_default().push(j);
+    }
+    let mut next = 0;
+    diff_lines
+        .iter()
+        .map(|line| {
+            let line = squash(line);
+            if line.is_empty() {
+                return None;
+            }
+            if let Some(found) = positions.get(line.as_str())
+                && let Some(&j) = found.get(found.partition_point(|&p| p < next))
+            {
+                next = j + 1;
+                return Some(j);
+            }
+            let window = next.saturating_sub(1)..(next + 50).min(keys.len());
+            let j = window.into_iter().find(|&j| keys[j].contains(line.as_str()))?;
+            next = j;
+            Some(j)
+        })
+        .collect()
+}
+
+/// 章・節・小節ごとに変更の数と増減した語数を木の形で表示する。
+pub fn print_summary(param: &Param) -> error::Result<()> {
+    let diff_tex = DiffTex::read(param.diff_tex_path())?;
+    let hunks = diff_tex.hunks();
+    let headings: Vec<Heading> = diff_tex.headings().into_iter().filter(|h| h.level <= 3).collect();
+    println!("Changes in {}.tex:", param.diff_docfile.to_string_lossy());
+    // `\input`したファイルはlatexdiffが(`--flatten`しないと)見ないので、変わったファイルも挙げておく
+    if let Some(baseline) = Baseline::from_param(param)?
+        && let Some(files) = baseline.changed_files()?
+        && !files.is_empty()
+    {
+        let since = match baseline.describe() {
+            Some(description) => format!("{} ({description})", param.old_revision()),
+            None => param.old_revision().to_string(),
+        };
+        let files: Vec<String> = files.iter().map(|f| f.display().to_string()).collect();
+        println!("  files changed since {since}: {}", files.join(", "));
+    }
+    if hunks.is_empty() {
+        println!("  no changes");
+        return Ok(());
+    }
+    let first = headings.first().map_or(usize::MAX, |h| h.start);
+    print_tally("  (before the first heading)", &hunks, 0..first);
+    let top = headings.iter().map(|h| h.level).min().unwrap_or(0);
+    for (i, heading) in headings.iter().enumerate() {
+        // 見出しの範囲は、同じかより上の階層の次の見出しまで(小節の変更は節にも数える)
+        let end = headings[i + 1..].iter().find(|h| h.level <= heading.level).map_or(usize::MAX, |h| h.start);
+        let indent = "  ".repeat(heading.level - top + 1);
+        print_tally(&format!("{indent}{}", heading.title), &hunks, heading.start..end);
+    }
+    Ok(())
+}
+
+/// `range`で始まる変更があれば、その数と語数を一行で表示する
+fn print_tally(label: &str, hunks: &[Hunk], range: Range<usize>) {
+    let inside: Vec<&Hunk> = hunks.iter().filter(|h| range.contains(&h.range.start)).collect();
+    if inside.is_empty() {
+        return;
+    }
+    let added: usize = inside.iter().map(|h| tex::word_count(&h.added)).sum();
+    let deleted: usize = inside.iter().map(|h| tex::word_count(&h.deleted)).sum();
+    println!("{label}: {} change(s), +{added} -{deleted} words", inside.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tex::aux::HunkPage;
+
+    #[test]
+    fn collects_changed_pages() {
+        let mut aux = Aux::default();
+        for (id, page, abs_page) in [(1, "ii", 2), (2, "1", 4), (3, "ii", 2), (4, "?", 0)] {
+            aux.hunks.insert(id, HunkPage { page: page.to_string(), abs_page });
+        }
+        let pages: Vec<_> = changed_pages(&aux).into_iter().collect();
+        assert_eq!(pages, [(0, ("?", 1)), (2, ("ii", 2)), (4, ("1", 1))]);
+    }
+}
@@ -0,0 +1,119 @@
+//! 本文中の引用と`.bib`の項目が古い版からどう変わったかをまとめる。
+//! latexdiffは`\cite`の中身の変化を目立たせないので、文献の変更はこちらで確認する。
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    baseline::Baseline,
+    error, osstr_join,
+    param::Param,
+    tex::{self, bib::Entry},
+};
+
+/// 本文で引用されているキー
+fn cited(src: &str) -> BTreeSet<String> {
+    let cmds = tex::commands(src, &[tex::CITE_CMDS, tex::TEXTUAL_CITE_CMDS, tex::OTHER_CITE_CMDS].concat(), 1);
+    cmds.iter().flat_map(|cmd| tex::split_list(cmd.args[0])).filter(|key| *key != "*").map(str::to_string).collect()
+}
+
+/// 文書が読み込んでいる全ての`.bib`の項目。キーが重なれば先のものを使う
+fn entries(src: &str, read: impl Fn(&Path) -> Option<Vec<u8>>) -> BTreeMap<String, Entry> {
+    let mut entries = BTreeMap::new();
+    for bib in tex::bib_files(src) {
+        let Some(bytes) = read(Path::new(&bib)) else { continue };
+        for entry in tex::bib::parse(&String::from_utf8_lossy(&bytes)) {
+            entries.entry(entry.key.clone()).or_insert(entry);
+        }
+    }
+    entries
+}
+
+/// `<doc>-citations.md`を差分PDFの隣に書き、件数を表示する
+pub fn report(param: &Param) -> error::Result<()> {
+    let Some(baseline) = Baseline::from_param(param)? else { return Ok(()) };
+    let old_src = baseline.flatten().text();
+    let new_src = tex::flatten(&PathBuf::from(osstr_join(&param.docfile, ".tex")), &mut tex::read_from_dir(&param.dir)).text();
+    let (old_cited, new_cited) = (cited(&old_src), cited(&new_src));
+    let old_entries = entries(&old_src, |path| baseline.read(path));
+    let new_entries = entries(&new_src, |path| std::fs::read(param.dir.join(path)).ok());
+
+    let cite_added: Vec<&String> = new_cited.difference(&old_cited).collect();
+    let cite_removed: Vec<&String> = old_cited.difference(&new_cited).collect();
+    let entry_added: Vec<&Entry> = new_entries.values().filter(|e| !old_entries.contains_key(&e.key)).collect();
+    let entry_removed: Vec<&Entry> = old_entries.values().filter(|e| !new_entries.contains_key(&e.key)).collect();
+    let entry_modified: Vec<(&Entry, &Entry)> =
+        old_entries.values().filter_map(|old| new_entries.get(&old.key).filter(|new| *new != old).map(|new| (old, new))).collect();
+
+    let mut md = format!("# Citation changes since {}\n\n## Citations in the text\n\n", param.old_revision());
+    let keys = |keys: &[&String]| keys.iter().map(|k| format!("`{k}`")).collect::<Vec<_>>().join(", ");
+    if cite_added.is_empty() && cite_removed.is_empty() {
+        md.push_str("No changes.\n");
+    }
+    if !cite_added.is_empty() {
+        md.push_str(&format!("- Added: {}\n", keys(&cite_added)));
+    }
+    if !cite_removed.is_empty() {
+        md.push_str(&format!("- Removed: {}\n", keys(&cite_removed)));
+    }
+    md.push_str("\n## Bibliography entries\n\n");
+    if entry_added.is_empty() && entry_removed.is_empty() && entry_modified.is_empty() {
+        md.push_str("No changes.\n");
+    }
+    for (title, list) in [("Added", &entry_added), ("Removed", &entry_removed)] {
+        if list.is_empty() {
+            continue;
+        }
+        md.push_str(&format!("### {title}\n\n"));
+        for entry in list.iter() {
+            let title = entry.fields.get("title").map_or(String::new(), |t| format!(": {t}"));
+            md.push_str(&format!("- `{}` ({}){title}\n", entry.key, entry.kind));
+        }
+        md.push('\n');
+    }
+    if !entry_modified.is_empty() {
+        md.push_str("### Modified\n\n");
+    }
+    for (old, new) in &entry_modified {
+        md.push_str(&format!("- `{}`\n", new.key));
+        if old.kind != new.kind {
+            md.push_str(&format!("  - type: {} -> {}\n", old.kind, new.kind));
+        }
+        let names: BTreeSet<&String> = old.fields.keys().chain(new.fields.keys()).collect();
+        for name in names {
+            match (old.fields.get(name), new.fields.get(name)) {
+                (Some(o), Some(n)) if o != n => md.push_str(&format!("  - {name}: \"{o}\" -> \"{n}\"\n")),
+                (None, Some(n)) => md.push_str(&format!("  - {name}: added \"{n}\"\n")),
+                (Some(o), None) => md.push_str(&format!("  - {name}: removed \"{o}\"\n")),
+                _ => {}
+            }
+        }
+    }
+
+    let path = param.diff_outdir().join(osstr_join(&param.docfile, "-citations.md"));
+    error::create_dir_all(param.diff_outdir())?;
+    error::write(&path, md)?;
+    println!(
+        "Citations: +{} -{} cited, bibliography entries +{} -{} ~{} ({})",
+        cite_added.len(),
+        cite_removed.len(),
+        entry_added.len(),
+        entry_removed.len(),
+        entry_modified.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_keys_after_pre_and_postnotes() {
+        let keys = cited("\\citep[see][p.~3]{a, b} \\parencite[ch.~2]{c} % \\cite{d}\n\\nocite{*}");
+        assert_eq!(keys, ["a", "b", "c"].map(String::from).into());
+    }
+}
@@ -1,8 +1,46 @@
 use clap::Args;
-use std::process::Command;
+use std::{
+    fs::File,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use crate::error::{self, Error};
+
+/// latexdiff-vcを通さず、二つの`.tex`を直接latexdiffにかける
+pub struct Latexdiff<'a> {
+    pub latexdiff: &'a Path,
+    pub old: &'a Path,
+    pub new: &'a Path,
+    pub flatten: bool,
+    pub verbose: bool,
+    pub opts: &'a Opts,
+}
+impl Latexdiff<'_> {
+    pub fn command(&self) -> Command {
+        let mut latexdiff = Command::new(self.latexdiff);
+        self.opts.args_to(self.verbose, &mut latexdiff);
+        if self.flatten {
+            // `\input`は各ファイルのあるディレクトリから解決される
+            latexdiff.arg("--flatten");
+        }
+        latexdiff.arg(self.old).arg(self.new);
+        latexdiff
+    }
+    /// 差分の`.tex`を`out`に書く(latexdiffは標準出力に出す)
+    pub fn run(&self, out: &Path) -> error::Result<Option<i32>> {
+        let file = File::create(out).map_err(|e| Error::FileWriteFailed { path: out.to_owned(), source: e })?;
+        let output = self.command().stdout(file).stderr(Stdio::piped()).output().map_err(Error::CommandFailed)?;
+        if !output.status.success() {
+            return Err(Error::StdErr(output.stderr));
+        }
+        Ok(output.status.code())
+    }
+}
 
 /// Configuration options for latexdiff command
 #[derive(Args, Debug, Clone)]
+#[group(id = "latexdiff_opts")]
 pub struct Opts {
     /// Markup style for \DIFadd and \DIFdel commands
     #[arg(short = 't', long = "type", value_name = "markupstyle")]
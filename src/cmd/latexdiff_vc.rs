@@ -19,28 +19,35 @@ pub struct LatexdiffVc<'a> {
 }
 impl LatexdiffVc<'_> {
     pub fn command(&self) -> Command {
-        let mut latexdiff = Command::new(&self.latexdiff_vc);
+        let mut latexdiff = Command::new(self.latexdiff_vc);
         self.latexdiff_opts.args_to(self.verbose, &mut latexdiff);
         self.opts.args_to(&mut latexdiff);
-        latexdiff.args(["-d", &self.diff_dir_name, "--force"]);
+        latexdiff.args(["-d", self.diff_dir_name, "--force"]);
         // current_dirからの相対指定でないとdiffフォルダに入れるのに失敗する(ここではファイル名のみでOK)
-        latexdiff.arg(OsString::from_iter([self.docfile, OsStr::new(".tex")])).current_dir(&self.dir);
+        latexdiff.arg(OsString::from_iter([self.docfile, OsStr::new(".tex")])).current_dir(self.dir);
         latexdiff
     }
     pub fn rename_tex(self) -> error::Result<()> {
-        error::create_dir_all(&self.tmpdir)?;
+        error::create_dir_all(self.tmpdir)?;
         error::rename(
             // DIFF_DIR_NAMEが存在していなかった場合も、latexdiff-vcが自動作成する
-            self.dir.join(&self.diff_dir_name).join(osstr_join(&self.docfile, ".tex")),
+            self.dir.join(self.diff_dir_name).join(osstr_join(self.docfile, ".tex")),
             // doc.texであればdoc_diff.texとかになる。
-            self.tmpdir.join(osstr_join(&self.diff_docfile, ".tex")),
+            self.tmpdir.join(osstr_join(self.diff_docfile, ".tex")),
         )?; // とりあえずさっさと移動。
         Ok(())
     }
 }
 
+/// latexdiff-vcを通さずにlatexdiffを直接使うことになる引数。latexdiff-vcにしか無い`--fast`と`--so`はこれらと併用できない
+const DIRECT_ARGS: &[&str] = &[
+    "hg", "jj", "against", "old_source", "only_paths", "exclude_paths", "take_new", "strip", "ignore_noise", "expand_macros", "series",
+    "series_tags", "branch_diff",
+];
+
 /// Configuration options for latexdiff-vc command
-#[derive(clap::Args, Debug)]
+#[derive(clap::Args, Debug, Clone)]
+#[group(id = "latexdiff_vc_opts")]
 pub struct Opts {
     /// Use Git for version control operations
     #[clap(long, group = "vcs")]
@@ -50,10 +57,14 @@ pub struct Opts {
     #[clap(long, group = "vcs")]
     pub svn: bool,
 
-    /// Use Mercurial (Hg) for version control operations
+    /// Use Mercurial (Hg); revisions are read with hg and diffed with latexdiff directly
     #[clap(long, group = "vcs")]
     pub hg: bool,
 
+    /// Use Jujutsu (jj); revisions like "@-" or change IDs are read with jj and diffed with latexdiff directly
+    #[clap(long, group = "vcs")]
+    pub jj: bool,
+
     /// Use CVS for version control operations
     #[clap(long, group = "vcs")]
     pub cvs: bool,
@@ -75,16 +86,16 @@ pub struct Opts {
     pub flatten_keep_intermediate: bool,
 
     /// Use latexdiff-fast
-    #[clap(long, group = "execution")]
+    #[clap(long, group = "execution", conflicts_with_all = DIRECT_ARGS)]
     fast: bool,
 
     /// Use latexdiff-so
-    #[clap(long, group = "execution")]
+    #[clap(long, group = "execution", conflicts_with_all = DIRECT_ARGS)]
     so: bool,
 
-    /// Only show pages with changes
+    /// Also write "<doc>-diff-changes.pdf" with only the pages that contain changes
     #[clap(long)]
-    only_changes: bool,
+    pub only_changes: bool,
 }
 impl Opts {
     pub fn args_to(&self, cmd: &mut Command) {
@@ -116,8 +127,5 @@ impl Opts {
         if self.so {
             cmd.arg("--so");
         }
-        if self.only_changes {
-            cmd.arg("--only-changes");
-        }
     }
 }
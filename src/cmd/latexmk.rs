@@ -15,14 +15,19 @@ pub struct LaTeXMK<'a> {
     pub tmpdir: &'a Path,
     pub outdir: Cow<'a, Path>,
     pub opts: &'a Opts,
+    /// 文書の前に読ませるコード(`-usepretex`)
+    pub pretex: Option<&'a str>,
 }
 impl LaTeXMK<'_> {
     pub fn command(&self) -> error::Result<Command> {
-        error::create_dir_all(&self.tmpdir)?;
+        error::create_dir_all(self.tmpdir)?;
         let mut cmd = Command::new(self.latexmk);
         self.opts.args_to(&mut cmd);
+        if let Some(pretex) = self.pretex {
+            cmd.arg(format!("-usepretex={pretex}"));
+        }
         cmd.args(["-outdir=", "-auxdir="].map(|key| OsString::from_iter([OsStr::new(key), self.tmpdir.as_os_str()])));
-        cmd.arg(self.dir.join(&self.docfile));
+        cmd.arg(self.dir.join(self.docfile));
         Ok(cmd)
     }
     pub fn rename_pdf(self) -> error::Result<()> {
@@ -37,7 +42,8 @@ impl LaTeXMK<'_> {
 }
 
 /// Configuration options for latexmk command
-#[derive(clap::Args, Debug)]
+#[derive(clap::Args, Debug, Clone)]
+#[group(id = "latexmk_opts")]
 pub struct Opts {
     /// Use XeLaTeX as the LaTeX engine
     #[clap(long, group = "engine")]
@@ -80,6 +86,12 @@ pub struct Opts {
     pub commands: bool,
 }
 impl Opts {
+    /// 出力に影響する設定(エンジン、参考文献処理、SyncTeX)。作ったPDFを使い回せるかの判断に使う
+    pub fn output_key(&self) -> String {
+        let engine = if self.xelatex { "xelatex" } else if self.lualatex { "lualatex" } else { "pdflatex" };
+        let bib = if self.bibtex { "bibtex" } else if self.biber { "biber" } else if self.nobibtex { "nobibtex" } else { "auto" };
+        format!("engine={engine} bib={bib} synctex={}", self.synctex)
+    }
     fn args_to(&self, cmd: &mut Command) {
         cmd.args(["-halt-on-error", "-file-line-error"]);
         if self.xelatex {
@@ -0,0 +1,191 @@
+//! 古い版と新しい版の語数・ページ数を数えて比べる。
+//! 語数はtexcountと同じく本文・キャプション・脚注を分けて数え、数式とコマンドは数えない。
+
+use std::path::{Path, PathBuf};
+
+use crate::{baseline::Baseline, error, osstr_join, param::Param, tex};
+
+/// 語数やページ数の上限を超えたときの終了コード
+pub const LIMIT_EXCEEDED: i32 = 3;
+
+const MATH_ENVS: &[&str] = &[
+    "equation", "equation*", "align", "align*", "gather", "gather*", "multline", "multline*", "eqnarray", "eqnarray*",
+    "displaymath", "math", "flalign", "flalign*",
+];
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct WordCount {
+    pub text: usize,
+    pub captions: usize,
+    pub footnotes: usize,
+}
+
+/// `src`(展開済みの文書全体)の`document`環境の中の語数
+pub fn count_words(src: &str) -> WordCount {
+    let body = tex::commands(src, &["begin"], 1).into_iter().find(|cmd| cmd.args[0].trim() == "document").map_or(src, |cmd| &src[cmd.end..]);
+    let body = body.find("\\end{document}").map_or(body, |end| &body[..end]);
+    let body: String = body.lines().map(|line| format!("{}\n", tex::strip_comment(line))).collect();
+    let body = strip_math(&body);
+
+    let mut count = WordCount::default();
+    let mut taken = Vec::new();
+    for (names, total) in [(&["caption"][..], &mut count.captions), (&["footnote", "footnotetext"][..], &mut count.footnotes)] {
+        for cmd in tex::commands(&body, names, 1) {
+            // キャプション中の脚注はキャプションとして数えてある
+            if taken.iter().any(|range: &std::ops::Range<usize>| range.contains(&cmd.start)) {
+                continue;
+            }
+            *total += tex::word_count(&tex::plain_text(cmd.args[0]));
+            taken.push(cmd.start..cmd.end);
+        }
+    }
+    // キャプションと脚注を除いた残りが本文
+    taken.sort_by_key(|range| range.start);
+    let mut text = String::new();
+    let mut at = 0;
+    for range in taken {
+        if range.start < at {
+            continue; // キャプション中の脚注など
+        }
+        text.push_str(&body[at..range.start]);
+        text.push(' ');
+        at = range.end;
+    }
+    text.push_str(&body[at..]);
+    count.text = tex::word_count(&tex::plain_text(&text));
+    count
+}
+
+/// 数式(`$..$`、`$$..$$`、`\(..\)`、`\[..\]`と数式環境)を空白にする。コメントは取り除いてあること
+fn strip_math(src: &str) -> String {
+    let mut out = String::new();
+    let mut rest = src;
+    while let Some(open) = rest.find(['$', '\\']) {
+        out.push_str(&rest[..open]);
+        let after = &rest[open..];
+        let close = if let Some(inner) = after.strip_prefix("$$") {
+            inner.find("$$").map(|e| e + 4)
+        } else if let Some(inner) = after.strip_prefix('$') {
+            inner.find('$').map(|e| e + 2)
+        } else if after.starts_with("\\(") {
+            after.find("\\)").map(|e| e + 2)
+        } else if after.starts_with("\\[") {
+            after.find("\\]").map(|e| e + 2)
+        } else if let Some(env) = MATH_ENVS.iter().find(|env| after.starts_with(&format!("\\begin{{{env}}}"))) {
+            let end = format!("\\end{{{env}}}");
+            after.find(&end).map(|e| e + end.len())
+        } else {
+            // `\$`などは数式の始まりではない
+            let len = after[1..].chars().next().map_or(1, |c| 1 + c.len_utf8());
+            out.push_str(&after[..len]);
+            rest = &after[len..];
+            continue;
+        };
+        let Some(close) = close else {
+            out.push_str(after);
+            return out;
+        };
+        out.push(' ');
+        rest = &after[close..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// `.log`の`Output written on ... (N pages, ...)`からページ数を読む。79文字で折り返されていてもよい
+pub fn pages_from_log(log: &Path) -> Option<usize> {
+    pages_in_log(&String::from_utf8_lossy(&std::fs::read(log).ok()?))
+}
+
+fn pages_in_log(log: &str) -> Option<usize> {
+    let log = log.replace(['\r', '\n'], "");
+    let at = log.rfind("Output written on")?;
+    let pages = log[at..].split(" (").nth(1)?;
+    pages.split(" page").next()?.trim().parse().ok()
+}
+
+/// 新しい版の本文の語数と、ビルドの`.log`から読んだページ数
+fn count_new(param: &Param) -> (WordCount, Option<usize>) {
+    let doc = PathBuf::from(osstr_join(&param.docfile, ".tex"));
+    let words = count_words(&tex::flatten(&doc, &mut tex::read_from_dir(&param.dir)).text());
+    (words, pages_from_log(&param.tmpdir.join(osstr_join(&param.docfile, ".log"))))
+}
+
+/// 両方の版の語数とページ数の差を表示する
+pub fn report(param: &Param) -> error::Result<()> {
+    let (new, new_pages) = count_new(param);
+    let (old, old_pages) = match Baseline::from_param(param)? {
+        Some(baseline) => {
+            // ページ数のために古い版もビルドする(ハッシュごとに一度だけ)。失敗してもページ数が分からないだけ
+            let old_pages = match baseline.build() {
+                Ok(Some(0)) => pages_from_log(&baseline.build_dir().join(osstr_join(&param.docfile, ".log"))),
+                _ => None,
+            };
+            (Some(count_words(&baseline.flatten().text())), old_pages)
+        }
+        None => (None, None),
+    };
+
+    println!("Word and page count ({}):", param.old_revision());
+    let row = |label: &str, old: Option<usize>, new: Option<usize>| match (old, new) {
+        (Some(old), Some(new)) => println!("  {label:<10}{old:>7} -> {new:>7} ({:+})", new as i64 - old as i64),
+        (None, Some(new)) => println!("  {label:<10}{:>7} -> {new:>7}", "?"),
+        (Some(old), None) => println!("  {label:<10}{old:>7} -> {:>7}", "?"),
+        (None, None) => {}
+    };
+    row("text", old.map(|c| c.text), Some(new.text));
+    row("captions", old.map(|c| c.captions), Some(new.captions));
+    row("footnotes", old.map(|c| c.footnotes), Some(new.footnotes));
+    row("pages", old_pages, new_pages);
+    Ok(())
+}
+
+/// `--max-words`と`--max-pages`を新しい版のビルドだけで確かめる。差分の成否に関わらず呼ぶ。超えていれば`true`
+pub fn exceeds_limits(param: &Param) -> bool {
+    if param.max_words.is_none() && param.max_pages.is_none() {
+        return false;
+    }
+    let (new, new_pages) = count_new(param);
+    let mut exceeded = false;
+    if let Some(max) = param.max_words
+        && new.text > max
+    {
+        println!("Word limit exceeded: {} > {max}", new.text);
+        exceeded = true;
+    }
+    if let (Some(max), Some(pages)) = (param.max_pages, new_pages)
+        && pages > max
+    {
+        println!("Page limit exceeded: {pages} > {max}");
+        exceeded = true;
+    }
+    exceeded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_text_captions_and_footnotes_apart() {
+        let src = "\\documentclass{article}\n\\title{Not counted}\n\\begin{document}\nOne two\\footnote{three four} five. % six\n\\begin{figure}\\caption{Seven eight\\footnote{nine}}\\end{figure}\nTen $x + y$ eleven.\n\\end{document}\nAfter.";
+        let count = count_words(src);
+        assert_eq!((count.text, count.captions, count.footnotes), (5, 3, 2));
+    }
+
+    #[test]
+    fn strips_math() {
+        assert_eq!(strip_math("a $x$ b $$y$$ c \\(z\\) d \\[w\\] e"), "a   b   c   d   e");
+        assert_eq!(strip_math("a \\begin{align*}x &= y\\end{align*} b"), "a   b");
+        // `\$`は数式ではなく、閉じていない数式はそのまま残す
+        assert_eq!(strip_math("costs \\$5 and $x"), "costs \\$5 and $x");
+    }
+
+    #[test]
+    fn reads_pages_from_a_wrapped_log() {
+        let log = "Output written on /very/long/path/to/the/build/directory/of/this/paper/main.p\ndf (12 pages, 34567 bytes).\n";
+        assert_eq!(pages_in_log(log), Some(12));
+        assert_eq!(pages_in_log("Output written on main.pdf (1 page, 100 bytes)."), Some(1));
+        assert_eq!(pages_in_log("No pages of output."), None);
+    }
+}
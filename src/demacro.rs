@@ -0,0 +1,195 @@
+//! `--expand-macros`。プリアンブルと手元の`.sty`にある単純なマクロを、latexdiffにかける前に本文中で展開する。
+//! latexdiffはマクロの中を見ないので、引数の中の変更が落ちたり、印がマクロを壊したりするのを避ける。
+//! 古い側と新しい側はそれぞれ自分の定義で展開するので、定義を変えた影響も本文の差分として出る。
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use crate::{error, tex};
+
+/// 本体にあれば展開しないコマンド(定義や条件分岐を含むものは単純ではない)
+const UNSAFE_CMDS: &[&str] = &[
+    "def", "gdef", "edef", "xdef", "let", "newcommand", "renewcommand", "providecommand", "makeatletter", "makeatother",
+    "csname", "expandafter", "ifx", "ifthenelse", "if", "else", "fi", "futurelet",
+];
+
+/// 展開できるマクロの名前と(引数の数, 本体)
+#[derive(Debug, Default)]
+pub struct Macros {
+    macros: BTreeMap<String, (usize, String)>,
+}
+impl Macros {
+    /// `main`のプリアンブルと、そこで読み込む手元の`.sty`から展開できる定義を集める。
+    /// `.sty`が`main`の隣に無ければ`fallback`から探す(書き直したツリーには`.tex`しか置かないため)
+    pub fn read(main: &Path, fallback: &Path, keep: &[String]) -> Macros {
+        let dir = main.parent().unwrap_or(Path::new("."));
+        let name = PathBuf::from(main.file_name().unwrap_or_default());
+        let src = tex::flatten(&name, &mut tex::read_from_dir(dir)).text();
+        let preamble = &src[..document_start(&src).unwrap_or(src.len())];
+        let mut sources = vec![preamble.to_string()];
+        for cmd in tex::commands(preamble, &["usepackage", "RequirePackage"], 1) {
+            for package in tex::split_list(cmd.args[0]) {
+                let file = tex::with_ext(package, "sty");
+                if let Some(sty) = [dir, fallback].iter().find_map(|dir| std::fs::read(dir.join(&file)).ok()) {
+                    sources.push(String::from_utf8_lossy(&sty).into_owned());
+                }
+            }
+        }
+        let mut macros: BTreeMap<String, Option<(usize, String)>> = BTreeMap::new();
+        for src in &sources {
+            for def in tex::definitions(src) {
+                let simple = simple(&def) && !keep.iter().any(|k| k == def.name);
+                let entry = simple.then(|| (def.nargs, def.body.to_string()));
+                // 違う定義が二つ以上あれば、どれが効くか分からないので展開しない
+                macros.entry(def.name.to_string()).and_modify(|old| if *old != entry { *old = None }).or_insert(entry);
+            }
+        }
+        Macros { macros: macros.into_iter().filter_map(|(name, def)| def.map(|def| (name, def))).collect() }
+    }
+
+    fn expand(&self, src: &str) -> String {
+        let mut src = src.to_string();
+        // 展開した本体にある別のマクロも展開する。循環していても止まるよう回数は限る
+        for _ in 0..8 {
+            let mut changed = false;
+            for nargs in 0..=9 {
+                let names: Vec<&str> = self.macros.iter().filter(|(_, (n, _))| *n == nargs).map(|(name, _)| name.as_str()).collect();
+                if names.is_empty() {
+                    continue;
+                }
+                let verbatim = tex::verbatim_regions(&src);
+                let mut edits = Vec::new();
+                for cmd in tex::commands(&src, &names, nargs) {
+                    // `\foo*`や`\foo[...]`は別の形の呼び出しなので触らない。verbatimの中は文字のまま組まれる
+                    if src[cmd.start + 1 + cmd.name.len()..].starts_with('*') || cmd.opt.is_some() || verbatim.iter().any(|r| r.contains(&cmd.start)) {
+                        continue;
+                    }
+                    let (_, body) = &self.macros[cmd.name];
+                    let text = substitute(body, &cmd.args);
+                    let mut end = cmd.end;
+                    // 引数無しのマクロの後の空白はTeXが読み捨てる。本体がコマンドで終われば、そのコマンドが同じように読み捨てる
+                    if nargs == 0 && !ends_with_control_word(&text) {
+                        end += src[end..].len() - src[end..].trim_start_matches([' ', '\t']).len();
+                    }
+                    edits.push((cmd.start..end, text));
+                }
+                changed |= !edits.is_empty();
+                for (range, text) in edits.into_iter().rev() {
+                    src.replace_range(range, &text);
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        src
+    }
+
+    /// `main`から取り込まれる`.tex`の本文を展開して`dest`に置き、メインの`.tex`を返す
+    fn rewrite(&self, main: &Path, dest: &Path) -> error::Result<PathBuf> {
+        let src_dir = main.parent().unwrap_or(Path::new("."));
+        let name = PathBuf::from(main.file_name().unwrap_or_default());
+        let mut read = tex::read_from_dir(src_dir);
+        for file in tex::flatten(&name, &mut read).files {
+            let Some(src) = read(&file) else { continue };
+            // プリアンブルの定義はそのまま残す
+            let body_start = if file == name { document_start(&src).unwrap_or(0) } else { 0 };
+            let expanded = format!("{}{}", &src[..body_start], self.expand(&src[body_start..]));
+            let path = dest.join(&file);
+            error::create_dir_all(path.parent().unwrap_or(dest))?;
+            error::write(path, expanded)?;
+        }
+        Ok(dest.join(name))
+    }
+}
+
+/// 古い側と新しい側をそれぞれの定義で展開して`dir/old`と`dir/new`に置き、それぞれのメインの`.tex`を返す
+pub fn apply(macros: &(Macros, Macros), old: &Path, new: &Path, dir: &Path) -> error::Result<(PathBuf, PathBuf)> {
+    error::remove_dir_all(dir)?;
+    Ok((macros.0.rewrite(old, &dir.join("old"))?, macros.1.rewrite(new, &dir.join("new"))?))
+}
+
+/// 省略できる引数や区切り付きの引数を取らず、本体が自分自身や定義、条件分岐を含まないもの
+fn simple(def: &tex::Definition) -> bool {
+    if def.command == "DeclareMathOperator" || def.default.is_some() || def.delimited || !def.name.chars().all(|c| c.is_ascii_alphabetic()) {
+        return false;
+    }
+    if def.body.contains('@') || !tex::commands(def.body, &[def.name], 0).is_empty() || !tex::commands(def.body, UNSAFE_CMDS, 0).is_empty() {
+        return false;
+    }
+    // `\#`以外の`#`は引数の参照(`#1`から`#n`)だけ
+    let bytes = def.body.as_bytes();
+    (0..bytes.len())
+        .filter(|&i| bytes[i] == b'#' && (i == 0 || bytes[i - 1] != b'\\'))
+        .all(|i| bytes.get(i + 1).is_some_and(|d| (b'1'..=b'9').contains(d) && usize::from(d - b'0') <= def.nargs))
+}
+
+/// 本体の`#1`から`#9`を引数に置き換える。置き換えた引数の中の`#n`はそのまま残すよう、本体を一度だけ読む
+fn substitute(body: &str, args: &[&str]) -> String {
+    let mut out = String::with_capacity(body.len());
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            // `\#`は引数の参照ではない
+            '\\' => {
+                out.push(c);
+                out.extend(chars.next());
+            }
+            '#' => {
+                let next = chars.next();
+                match next.and_then(|d| d.to_digit(10)).and_then(|n| args.get((n as usize).checked_sub(1)?)) {
+                    Some(arg) => out.push_str(arg),
+                    None => out.extend(std::iter::once(c).chain(next)),
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn ends_with_control_word(text: &str) -> bool {
+    let word = text.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    word.len() < text.len() && word.ends_with('\\')
+}
+
+fn document_start(src: &str) -> Option<usize> {
+    tex::commands(src, &["begin"], 1).into_iter().find(|cmd| cmd.args[0].trim() == "document").map(|cmd| cmd.end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn macros(defs: &[(&str, usize, &str)]) -> Macros {
+        Macros { macros: defs.iter().map(|&(name, n, body)| (name.to_string(), (n, body.to_string()))).collect() }
+    }
+
+    #[test]
+    fn substitutes_arguments_once() {
+        assert_eq!(substitute("(#1, #2) \\#1", &["#2", "b"]), "(#2, b) \\#1");
+        // 展開した本体の`\\R`の後の空白も、TeXと同じく読み捨てる
+        let m = macros(&[("pair", 2, "(#1, #2)"), ("R", 0, "\\mathbb{R}"), ("vR", 1, "\\vec{#1}\\R")]);
+        assert_eq!(m.expand("\\pair{x}{y} \\vR{v} \\R x"), "(x, y) \\vec{v}\\mathbb{R}\\mathbb{R}x");
+    }
+
+    #[test]
+    fn leaves_verbatim_alone() {
+        let m = macros(&[("R", 0, "\\mathbb{R}")]);
+        let src = "\\R \\verb|\\R| \\verb*+\\R+\n\\begin{verbatim}\n\\R\n\\end{verbatim}\n\\R\n";
+        assert_eq!(m.expand(src), "\\mathbb{R}\\verb|\\R| \\verb*+\\R+\n\\begin{verbatim}\n\\R\n\\end{verbatim}\n\\mathbb{R}\n");
+    }
+
+    #[test]
+    fn skips_complex_definitions() {
+        let simple_def = |src: &str| tex::definitions(src).first().is_some_and(simple);
+        assert!(simple_def("\\newcommand{\\pair}[2]{(#1, #2)}"));
+        assert!(!simple_def("\\newcommand{\\opt}[2][x]{#1#2}"));
+        assert!(!simple_def("\\newcommand{\\loop}{\\loop}"));
+        assert!(!simple_def("\\newcommand{\\at}{\\my@cmd}"));
+        assert!(!simple_def("\\newcommand{\\cond}{\\ifx a b\\fi}"));
+        assert!(!simple_def("\\newcommand{\\bad}[1]{#2}"));
+    }
+}
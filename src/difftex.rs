@@ -0,0 +1,345 @@
+//! latexdiffが出力した差分`.tex`に後から手を入れる。
+
+use std::{collections::HashMap, ops::Range, path::PathBuf};
+
+use crate::{
+    error, tex,
+    tex::aux::{Aux, Label},
+};
+
+const ADD_BEGIN: &[&str] = &["DIFaddbegin", "DIFaddbeginFL"];
+const ADD_END: &[&str] = &["DIFaddend", "DIFaddendFL"];
+const DEL_BEGIN: &[&str] = &["DIFdelbegin", "DIFdelbeginFL"];
+const DEL_END: &[&str] = &["DIFdelend", "DIFdelendFL"];
+const HEADINGS: &[&str] = &["part", "chapter", "section", "subsection", "subsubsection"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HunkKind {
+    Add,
+    Delete,
+    Replace,
+}
+
+/// ひとまとまりの変更。削除の直後に追加が続くものは置換として一つにまとめる。
+#[derive(Debug, Clone)]
+pub struct Hunk {
+    pub kind: HunkKind,
+    /// `\DIFhunk{n}`の番号(置換なら削除と追加の二つ)
+    pub ids: Vec<usize>,
+    pub range: Range<usize>,
+    /// 削除された文と追加された文(本文のみ)
+    pub deleted: String,
+    pub added: String,
+}
+
+/// 見出し。`level`は`part`が0、`chapter`が1、……
+#[derive(Debug, Clone)]
+pub struct Heading {
+    pub level: usize,
+    pub title: String,
+    pub start: usize,
+}
+
+pub struct DiffTex {
+    path: PathBuf,
+    pub src: String,
+}
+impl DiffTex {
+    pub fn read(path: PathBuf) -> error::Result<DiffTex> {
+        let src = String::from_utf8_lossy(&error::read(&path)?).into_owned();
+        Ok(DiffTex { path, src })
+    }
+    pub fn write(&self) -> error::Result<()> {
+        error::write(&self.path, &self.src)
+    }
+
+    /// `\DIFdelbegin`から`\DIFdelend`までの範囲
+    pub fn deleted_regions(&self) -> Vec<Range<usize>> {
+        regions(&self.src, DEL_BEGIN, DEL_END)
+    }
+
+    /// 削除部分の`\includegraphics`が古い側から取り出した画像を指すように書き換える。
+    /// そのままだと同じパスにある新しい画像が表示されるか、消えた画像が見つからずに失敗する。
+    pub fn redirect_deleted_graphics(&mut self, graphics: &HashMap<String, PathBuf>) {
+        let deleted = self.deleted_regions();
+        let mut replaces = Vec::new();
+        for cmd in tex::commands(&self.src, &["includegraphics"], 1) {
+            if !deleted.iter().any(|r| r.contains(&cmd.start)) {
+                continue;
+            }
+            if let Some(old) = graphics.get(cmd.args[0].trim()) {
+                // TeXにはバックスラッシュ区切りを渡せないので揃えておく
+                replaces.push((cmd.arg_ranges[0].clone(), old.to_string_lossy().replace('\\', "/")));
+            }
+        }
+        self.replace_all(replaces);
+    }
+
+    /// latexdiffと同じく、`\begin{document}`の直前にプリアンブルを書き足す。
+    pub fn add_preamble(&mut self, lines: &[&str]) {
+        let begin = tex::commands(&self.src, &["begin"], 1).into_iter().find(|cmd| cmd.args[0].trim() == "document");
+        let at = begin.map_or(0, |cmd| cmd.start);
+        let block: String = lines.iter().map(|line| format!("{line} %DIF PREAMBLE\n")).collect();
+        self.src.insert_str(at, &block);
+    }
+
+    /// `\begin{document}`の直後(本文の最初)に書き足す。
+    pub fn add_front_page(&mut self, page: &str) {
+        let begin = tex::commands(&self.src, &["begin"], 1).into_iter().find(|cmd| cmd.args[0].trim() == "document");
+        let at = begin.map_or(self.src.len(), |cmd| cmd.end);
+        self.src.insert_str(at, &format!("\n{page}"));
+    }
+
+    /// 削除部分の`\ref`や`\cite`を、古い側の`.aux`にある番号で置き換える。
+    /// 消えたラベルが`??`になったり、番号が変わったラベルが新しい番号で表示されたりするのを防ぐ。
+    pub fn resolve_deleted_refs(&mut self, old: &Aux) {
+        let deleted = self.deleted_regions();
+        let in_deleted = |start: usize| deleted.iter().any(|r| r.contains(&start));
+        let mut replaces = Vec::new();
+        for cmd in tex::commands(&self.src, tex::REF_CMDS, 1) {
+            // `\cref{a,b}`のように複数のこともある。一つでも分からなければそのままにする
+            let labels: Option<Vec<&Label>> = tex::split_list(cmd.args[0]).map(|key| old.labels.get(key)).collect();
+            let Some(labels) = labels else { continue };
+            if !in_deleted(cmd.start) {
+                continue;
+            }
+            let text = labels.iter().map(|label| ref_text(cmd.name, label)).collect::<Vec<_>>().join(", ");
+            replaces.push((cmd.start..cmd.end, format!("\\DIFoldref{{{}}}{{{text}}}", cmd.args[0])));
+        }
+        for cmd in tex::commands(&self.src, &[tex::CITE_CMDS, tex::TEXTUAL_CITE_CMDS].concat(), 1) {
+            let keys: Vec<&str> = tex::split_list(cmd.args[0]).collect();
+            let numbers: Option<Vec<&str>> = keys.iter().map(|key| old.cites.get(*key).map(String::as_str)).collect();
+            let Some(mut numbers) = numbers else { continue }; // 一つでも分からなければそのままにする
+            if !in_deleted(cmd.start) {
+                continue;
+            }
+            if tex::TEXTUAL_CITE_CMDS.contains(&cmd.name) {
+                // 著者名はnatbibの`.aux`にしか無い
+                let authors: Option<Vec<String>> =
+                    keys.iter().zip(&numbers).map(|(key, n)| old.cite_authors.get(*key).map(|a| format!("{a} [{n}]"))).collect();
+                let Some(authors) = authors else { continue };
+                replaces.push((cmd.start..cmd.end, format!("\\DIFoldref{{{}}}{{{}}}", cmd.args[0], authors.join(", "))));
+                continue;
+            }
+            numbers.extend(cmd.opt);
+            replaces.push((cmd.start..cmd.end, format!("\\DIFoldcite{{{}}}{{{}}}", cmd.args[0], numbers.join(", "))));
+        }
+        if replaces.is_empty() {
+            return;
+        }
+        self.replace_all(replaces);
+        self.add_preamble(&["\\providecommand{\\DIFoldref}[2]{#2}", "\\providecommand{\\DIFoldcite}[2]{[#2]}"]);
+    }
+
+    /// 追加・削除の始まりごとに通し番号付きの`\DIFhunk{n}`を差し込み、出力されたページを`.aux`に書かせる。
+    pub fn mark_hunks(&mut self) {
+        let names: Vec<&str> = ADD_BEGIN.iter().chain(DEL_BEGIN).copied().collect();
+        let marks: Vec<_> = tex::commands(&self.src, &names, 0)
+            .into_iter()
+            .enumerate()
+            // 後ろに差し込むと`\DIFaddbegin`が食べていた空白が出てしまうので、前に差し込む
+            .map(|(n, cmd)| (cmd.start..cmd.start, format!("\\DIFhunk{{{}}}", n + 1)))
+            .collect();
+        self.replace_all(marks);
+        // ページ番号は出力時に展開される。物理的なページ番号も一緒に書いておく
+        self.add_preamble(&[
+            "\\makeatletter",
+            "\\providecommand{\\DIFhunkpage}[3]{}",
+            "\\def\\DIF@abspage{\\ifdefined\\ReadonlyShipoutCounter\\the\\ReadonlyShipoutCounter\\else\\the\\c@page\\fi}",
+            "\\DeclareRobustCommand{\\DIFhunk}[1]{\\protected@write\\@auxout{}{\\string\\DIFhunkpage{#1}{\\thepage}{\\DIF@abspage}}}",
+            "\\makeatother",
+        ]);
+    }
+
+    /// `mark_hunks`した後の差分から変更を文書順に取り出す。
+    pub fn hunks(&self) -> Vec<Hunk> {
+        let ends: Vec<&str> = ADD_END.iter().chain(DEL_END).copied().collect();
+        let ends = tex::commands(&self.src, &ends, 0);
+        let mut hunks: Vec<Hunk> = Vec::new();
+        for mark in tex::commands(&self.src, &["DIFhunk"], 1) {
+            let Ok(id) = mark.args[0].trim().parse() else { continue };
+            let Some(begin) = tex::commands(&self.src[mark.end..], &[ADD_BEGIN, DEL_BEGIN].concat(), 0).into_iter().next() else { continue };
+            let adding = ADD_BEGIN.contains(&begin.name);
+            let family = if adding { ADD_END } else { DEL_END };
+            let Some(end) = ends.iter().find(|cmd| cmd.start > mark.end && family.contains(&cmd.name)) else { continue };
+            let body = &self.src[mark.end..end.end];
+            let text = tex::plain_text(&if adding { unwrap_added(body) } else { unwrap_deleted(body) });
+            // 削除と追加の間に空白しか無ければ置換
+            if adding
+                && let Some(prev) = hunks.last_mut()
+                && prev.kind == HunkKind::Delete
+                && self.src[prev.range.end..mark.start].trim().is_empty()
+            {
+                prev.kind = HunkKind::Replace;
+                prev.ids.push(id);
+                prev.range.end = end.end;
+                prev.added = text;
+                continue;
+            }
+            let (kind, deleted, added) = if adding { (HunkKind::Add, String::new(), text) } else { (HunkKind::Delete, text, String::new()) };
+            hunks.push(Hunk { kind, ids: vec![id], range: mark.start..end.end, deleted, added });
+        }
+        hunks
+    }
+
+    /// 新しい側にある見出し(削除された見出しは含まない)
+    pub fn headings(&self) -> Vec<Heading> {
+        let deleted = self.deleted_regions();
+        tex::commands(&self.src, HEADINGS, 1)
+            .into_iter()
+            .filter(|cmd| !deleted.iter().any(|r| r.contains(&cmd.start)))
+            .map(|cmd| Heading {
+                level: HEADINGS.iter().position(|&h| h == cmd.name).unwrap(),
+                title: tex::plain_text(cmd.args[0]),
+                start: cmd.start,
+            })
+            .collect()
+    }
+
+    /// 各行を新しい側のソースの形に戻したもの。削除部分と差分のマークアップ、コメントを取り除き、空白をまとめる。
+    /// 行の数と並びは差分`.tex`のまま。
+    pub fn new_side_lines(&self) -> Vec<String> {
+        // 削除部分は改行だけ残して消す
+        let mut masked = self.src.clone().into_bytes();
+        for range in self.deleted_regions() {
+            for b in &mut masked[range] {
+                if *b != b'\n' {
+                    *b = b' ';
+                }
+            }
+        }
+        let masked = String::from_utf8_lossy(&masked).into_owned();
+        masked.lines().map(|line| tex::collapse_ws(&unwrap_added(tex::strip_comment(line)))).collect()
+    }
+
+    /// 重ならない範囲の置換をまとめて行う。後ろから置換するので範囲は元の位置のままでよい。
+    pub fn replace_all(&mut self, mut replaces: Vec<(Range<usize>, String)>) {
+        replaces.sort_by_key(|(range, _)| std::cmp::Reverse(range.start));
+        for (range, text) in replaces {
+            self.src.replace_range(range, &text);
+        }
+    }
+}
+
+/// 参照のコマンドが古い版で出力していた文字列。cleverefやhyperrefの参照には種類(`figure`など)が分かれば付ける
+fn ref_text(cmd: &str, label: &Label) -> String {
+    let number = if label.kind.as_deref() == Some("equation") { format!("({})", label.number) } else { label.number.clone() };
+    match (cmd, &label.kind) {
+        ("eqref", _) => format!("({})", label.number),
+        ("pageref", _) => label.page.clone(),
+        ("cref", Some(kind)) => format!("{kind} {number}"),
+        ("Cref" | "autoref" | "Autoref", Some(kind)) => {
+            let mut chars = kind.chars();
+            let kind: String = chars.next().map(|c| c.to_uppercase().chain(chars).collect()).unwrap_or_default();
+            format!("{kind} {number}")
+        }
+        _ => label.number.clone(),
+    }
+}
+
+/// `begin`と`end`の組で囲まれた範囲を列挙する。閉じられていなければ末尾までとする。
+fn regions(src: &str, begin: &[&str], end: &[&str]) -> Vec<Range<usize>> {
+    let names: Vec<&str> = begin.iter().chain(end).copied().collect();
+    let mut found = Vec::new();
+    let mut open = None;
+    for cmd in tex::commands(src, &names, 0) {
+        if begin.contains(&cmd.name) {
+            open.get_or_insert(cmd.start);
+        } else if let Some(start) = open.take() {
+            found.push(start..cmd.end);
+        }
+    }
+    if let Some(start) = open {
+        found.push(start..src.len());
+    }
+    found
+}
+
+/// 追加部分のマークアップを外す(`\DIFadd{x}`は`x`に、`\DIFaddbegin`などは消す)
+fn unwrap_added(src: &str) -> String {
+    unwrap_markup(&strip_author(src), &["DIFadd", "DIFaddFL"], &[ADD_BEGIN, ADD_END].concat())
+}
+/// 著者ごとに色分けした`\DIFaddauA`などを`\DIFadd`に戻す(blame::apply)
+fn strip_author(src: &str) -> String {
+    let mut out = String::new();
+    let mut rest = src;
+    while let Some(at) = rest.find("\\DIFadd") {
+        let after = &rest[at + "\\DIFadd".len()..];
+        let fl = if after.starts_with("FL") { "FL" } else { "" };
+        out.push_str(&rest[..at]);
+        out.push_str("\\DIFadd");
+        out.push_str(fl);
+        rest = &after[fl.len()..];
+        if let Some(letters) = rest.strip_prefix("au") {
+            let len = letters.find(|c: char| !c.is_ascii_uppercase()).unwrap_or(letters.len());
+            if len > 0 {
+                rest = &letters[len..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+/// 削除部分のマークアップを外す
+fn unwrap_deleted(src: &str) -> String {
+    unwrap_markup(src, &["DIFdel", "DIFdelFL"], &[DEL_BEGIN, DEL_END].concat())
+}
+
+fn unwrap_markup(src: &str, wrappers: &[&str], markers: &[&str]) -> String {
+    let mut replaces: Vec<(Range<usize>, &str)> = Vec::new();
+    for cmd in tex::commands(src, wrappers, 1) {
+        replaces.push((cmd.start..cmd.end, cmd.args[0]));
+    }
+    for cmd in tex::commands(src, &["DIFhunk"], 1).into_iter().chain(tex::commands(src, &["DIFblame"], 2)) {
+        replaces.push((cmd.start..cmd.end, ""));
+    }
+    for cmd in tex::commands(src, markers, 0) {
+        replaces.push((cmd.start..cmd.end, ""));
+    }
+    replaces.sort_by_key(|(range, _)| range.start);
+    let mut out = String::new();
+    let mut at = 0;
+    for (range, text) in replaces {
+        if range.start < at {
+            continue;
+        }
+        out.push_str(&src[at..range.start]);
+        out.push_str(text);
+        at = range.end;
+    }
+    out.push_str(&src[at..]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirects_only_deleted_graphics() {
+        let graphics = HashMap::from([("a".to_string(), PathBuf::from("/tmp/old/fig/a.png"))]);
+        let body = "\\DIFdelbegin \\DIFdel{\\includegraphics[width=1cm]{a}}\\DIFdelend \\DIFaddbegin \\DIFadd{\\includegraphics{a}}\\DIFaddend \\includegraphics{a}";
+        let mut diff = DiffTex { path: PathBuf::new(), src: body.to_string() };
+        diff.redirect_deleted_graphics(&graphics);
+        assert_eq!(
+            diff.src,
+            "\\DIFdelbegin \\DIFdel{\\includegraphics[width=1cm]{/tmp/old/fig/a.png}}\\DIFdelend \\DIFaddbegin \\DIFadd{\\includegraphics{a}}\\DIFaddend \\includegraphics{a}"
+        );
+    }
+
+    #[test]
+    fn resolves_refs_and_cites_in_deleted_text() {
+        let mut aux = Aux::default();
+        aux.labels.insert("fig:a".into(), Label { number: "2".into(), page: "5".into(), kind: Some("figure".into()) });
+        aux.labels.insert("eq:b".into(), Label { number: "3".into(), page: "6".into(), kind: Some("equation".into()) });
+        aux.cites.insert("k".into(), "7".into());
+        aux.cite_authors.insert("k".into(), "Smith".into());
+        let body = "\\DIFdelbegin \\DIFdel{\\Cref{fig:a} \\cref{eq:b} \\citep[see][p.~3]{k} \\citet{k} \\ref{gone}}\\DIFdelend \\cref{fig:a}";
+        let mut diff = DiffTex { path: PathBuf::new(), src: format!("\\documentclass{{article}}\n\\begin{{document}}\n{body}\n\\end{{document}}\n") };
+        diff.resolve_deleted_refs(&aux);
+        assert!(diff.src.contains(
+            "\\DIFdel{\\DIFoldref{fig:a}{Figure 2} \\DIFoldref{eq:b}{equation (3)} \\DIFoldcite{k}{7, p.~3} \\DIFoldref{k}{Smith [7]} \\ref{gone}}\\DIFdelend \\cref{fig:a}"
+        ));
+    }
+}
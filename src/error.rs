@@ -9,9 +9,18 @@ pub type Result<T, E = Error> = std::result::Result<T, E>;
 pub enum Error {
     CurrentDirFailed(io::Error),
     CreateDirFailed { path: PathBuf, source: io::Error },
+    RemoveDirFailed { path: PathBuf, source: io::Error },
     CanonicalizeFailed { path: PathBuf, source: io::Error },
     FileCopyFailed { from: PathBuf, to: PathBuf, source: io::Error },
     FileRenameFailed { from: PathBuf, to: PathBuf, source: io::Error },
+    FileReadFailed { path: PathBuf, source: io::Error },
+    FileWriteFailed { path: PathBuf, source: io::Error },
+    LockFailed { path: PathBuf, source: io::Error },
+    PdfFailed { path: PathBuf, source: lopdf::Error },
+    ParseFailed { path: PathBuf, message: String },
+    NoMatchingTag(String),
+    NotInArchive { archive: PathBuf, name: PathBuf },
+    TooManyRevisions(usize),
     AlreadySaid,
     StdIoError(io::Error),
     CommandFailed(io::Error),
@@ -28,15 +37,40 @@ pub fn rename(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<()> {
         source: e,
     })
 }
+pub fn read(path: impl AsRef<Path>) -> Result<Vec<u8>> {
+    std::fs::read(&path).map_err(|e| Error::FileReadFailed { path: path.as_ref().to_owned(), source: e })
+}
+pub fn write(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()> {
+    std::fs::write(&path, contents).map_err(|e| Error::FileWriteFailed { path: path.as_ref().to_owned(), source: e })
+}
 pub fn create_dir_all(path: impl AsRef<Path>) -> Result<()> {
     std::fs::create_dir_all(&path).map_err(|e| Error::CreateDirFailed { path: path.as_ref().to_owned(), source: e })
 }
+/// 前回の残りを中身ごと消す。無ければ何もしない
+pub fn remove_dir_all(path: impl AsRef<Path>) -> Result<()> {
+    match std::fs::remove_dir_all(&path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(Error::RemoveDirFailed { path: path.as_ref().to_owned(), source: e }),
+        _ => Ok(()),
+    }
+}
+/// `path`を作って排他的にロックする。ほかのプロセスが持っていれば解放されるまで待つ。返したファイルを閉じると解放される
+pub fn lock(path: impl AsRef<Path>) -> Result<std::fs::File> {
+    let error = |e| Error::LockFailed { path: path.as_ref().to_owned(), source: e };
+    let file = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(&path).map_err(error)?;
+    file.lock().map_err(error)?;
+    Ok(file)
+}
 pub fn canonicalize(path: impl AsRef<Path>) -> Result<PathBuf> {
     std::fs::canonicalize(&path).map_err(|e| Error::CanonicalizeFailed { path: path.as_ref().to_owned(), source: e })
 }
 
 impl Error {
     pub fn print_and_exit<T>(self) -> T {
+        self.print();
+        std::process::exit(1);
+    }
+    /// 終了せずにエラーを表示する(他の処理を続ける場合)
+    pub fn print(self) {
         match self {
             Error::AlreadySaid => {
                 // Already printed, nothing to do
@@ -52,6 +86,9 @@ impl Error {
             Error::CreateDirFailed { path, source } => {
                 eprintln!("Failed to create directory {}: {}", path.display(), source);
             }
+            Error::RemoveDirFailed { path, source } => {
+                eprintln!("Failed to remove directory {}: {}", path.display(), source);
+            }
             Error::CanonicalizeFailed { path, source } => {
                 eprintln!("Failed to canonicalize path {}: {}", path.display(), source);
             }
@@ -61,6 +98,30 @@ impl Error {
             Error::FileRenameFailed { from, to, source } => {
                 eprintln!("Failed to rename file {} -> {}: {}", from.display(), to.display(), source);
             }
+            Error::FileReadFailed { path, source } => {
+                eprintln!("Failed to read file {}: {}", path.display(), source);
+            }
+            Error::FileWriteFailed { path, source } => {
+                eprintln!("Failed to write file {}: {}", path.display(), source);
+            }
+            Error::LockFailed { path, source } => {
+                eprintln!("Failed to lock {}: {}", path.display(), source);
+            }
+            Error::PdfFailed { path, source } => {
+                eprintln!("Failed to process PDF {}: {}", path.display(), source);
+            }
+            Error::ParseFailed { path, message } => {
+                eprintln!("Failed to parse {}: {}", path.display(), message);
+            }
+            Error::NoMatchingTag(pattern) => {
+                eprintln!("No tag matches {}", pattern);
+            }
+            Error::NotInArchive { archive, name } => {
+                eprintln!("No {} found in {}", name.display(), archive.display());
+            }
+            Error::TooManyRevisions(n) => {
+                eprintln!("At most two --revision values can be given when latexdiff is run directly, but {} were given", n);
+            }
             Error::StdIoError(e) => {
                 eprintln!("I/O error occurred: {}", e);
             }
@@ -71,6 +132,5 @@ impl Error {
                 eprintln!("Failed to get environment information: {}", e);
             }
         }
-        std::process::exit(1);
     }
 }
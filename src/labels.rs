@@ -0,0 +1,205 @@
+//! 古い版と新しい版の`.aux`を比べ、ラベルの番号がどう変わったかの対応表を作る。
+//! 回答書の「旧図3は新図4」を書くためのもの。
+
+use std::collections::BTreeSet;
+
+use crate::{
+    baseline::Baseline,
+    error::{self, Error},
+    osstr_join,
+    param::Param,
+    tex::{
+        self,
+        aux::{Aux, Label},
+    },
+};
+
+/// 対応表の形式
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Md,
+    Json,
+    Tex,
+}
+impl Format {
+    fn ext(self) -> &'static str {
+        match self {
+            Format::Md => "md",
+            Format::Json => "json",
+            Format::Tex => "tex",
+        }
+    }
+}
+
+/// ラベルの名前の付け方から種類を推測する(cleverefもhyperrefも無い場合)
+const PREFIXES: &[(&str, &str)] = &[
+    ("fig", "figure"),
+    ("tab", "table"),
+    ("tbl", "table"),
+    ("eq", "equation"),
+    ("eqn", "equation"),
+    ("sec", "section"),
+    ("ssec", "subsection"),
+    ("subsec", "subsection"),
+    ("ch", "chapter"),
+    ("chap", "chapter"),
+    ("app", "appendix"),
+    ("thm", "theorem"),
+    ("lem", "lemma"),
+    ("prop", "proposition"),
+    ("cor", "corollary"),
+    ("def", "definition"),
+    ("alg", "algorithm"),
+    ("lst", "listing"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Added,
+    Removed,
+    Renumbered,
+    Unchanged,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Renumbering {
+    pub label: String,
+    pub kind: String,
+    pub old_number: Option<String>,
+    pub new_number: Option<String>,
+    pub old_page: Option<String>,
+    pub new_page: Option<String>,
+    pub status: Status,
+}
+
+/// 両方の`.aux`にあるラベルを種類と番号の順に並べた対応表
+pub fn renumbering(old: &Aux, new: &Aux) -> Vec<Renumbering> {
+    let keys: BTreeSet<&String> = old.labels.keys().chain(new.labels.keys()).collect();
+    let mut rows: Vec<Renumbering> = keys
+        .into_iter()
+        .map(|key| {
+            let (old, new) = (old.labels.get(key), new.labels.get(key));
+            let status = match (old, new) {
+                (None, _) => Status::Added,
+                (_, None) => Status::Removed,
+                (Some(o), Some(n)) if o.number != n.number => Status::Renumbered,
+                _ => Status::Unchanged,
+            };
+            Renumbering {
+                label: key.clone(),
+                kind: kind_of(key, new.or(old)),
+                // 番号に`\relax`などが混ざっていることがある
+                old_number: old.map(|l| tex::plain_text(&l.number)),
+                new_number: new.map(|l| tex::plain_text(&l.number)),
+                old_page: old.map(|l| tex::plain_text(&l.page)),
+                new_page: new.map(|l| tex::plain_text(&l.page)),
+                status,
+            }
+        })
+        .collect();
+    rows.sort_by_cached_key(|row| (row.kind.clone(), number_key(row.new_number.as_ref().or(row.old_number.as_ref())), row.label.clone()));
+    rows
+}
+
+fn kind_of(key: &str, label: Option<&Label>) -> String {
+    if let Some(kind) = label.and_then(|l| l.kind.clone()) {
+        return kind;
+    }
+    let prefix = key.split([':', '-', '_', '.']).next().unwrap_or("").to_lowercase();
+    PREFIXES.iter().find(|(p, _)| *p == prefix).map_or("other", |(_, kind)| kind).to_string()
+}
+
+/// `2.10`が`2.9`の後に来るように、数字の並びを数として比べる
+fn number_key(number: Option<&String>) -> Vec<(u64, String)> {
+    let Some(number) = number else { return Vec::new() };
+    number
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .map(|part| part.parse().map_or((u64::MAX, part.to_string()), |n| (n, String::new())))
+        .collect()
+}
+
+/// 対応表を`<doc>-labels.<ext>`として差分PDFの隣に書く
+pub fn write(param: &Param, format: Format) -> error::Result<()> {
+    let Some(baseline) = Baseline::from_param(param)? else { return Ok(()) };
+    if baseline.build()? != Some(0) {
+        return Err(Error::AlreadySaid);
+    }
+    let new = Aux::read(&param.tmpdir, &param.docfile.to_string_lossy())?;
+    let rows = renumbering(&baseline.aux()?, &new);
+    let text = match format {
+        Format::Md => markdown(&rows),
+        Format::Json => serde_json::to_string_pretty(&rows).expect("plain data"),
+        Format::Tex => latex(&rows),
+    };
+    let name = osstr_join(&param.docfile, &format!("-labels.{}", format.ext()));
+    error::create_dir_all(param.diff_outdir())?;
+    error::write(param.diff_outdir().join(name), text)
+}
+
+fn cells(row: &Renumbering) -> [String; 5] {
+    let or_dash = |s: &Option<String>| s.clone().unwrap_or_else(|| "-".to_string());
+    let page = match (&row.old_page, &row.new_page) {
+        (Some(old), Some(new)) if old != new => format!("{old} -> {new}"),
+        (old, new) => or_dash(if new.is_some() { new } else { old }),
+    };
+    let status = match row.status {
+        Status::Added => "added",
+        Status::Removed => "removed",
+        Status::Renumbered => "renumbered",
+        Status::Unchanged => "",
+    };
+    [row.kind.clone(), or_dash(&row.old_number), or_dash(&row.new_number), page, status.to_string()]
+}
+
+fn markdown(rows: &[Renumbering]) -> String {
+    let mut out = String::from("| Label | Type | Old | New | Page | Status |\n|---|---|---|---|---|---|\n");
+    for row in rows {
+        let [kind, old, new, page, status] = cells(row).map(|c| c.replace('|', "\\|"));
+        out.push_str(&format!("| `{}` | {kind} | {old} | {new} | {page} | {status} |\n", row.label));
+    }
+    out
+}
+
+/// `\input`して使う`tabular`
+fn latex(rows: &[Renumbering]) -> String {
+    let mut out = String::from("\\begin{tabular}{llllll}\n\\hline\nLabel & Type & Old & New & Page & Status \\\\\n\\hline\n");
+    for row in rows {
+        let [kind, old, new, page, status] = cells(row).map(|c| tex::escape(&c).replace("->", "$\\to$"));
+        out.push_str(&format!("\\texttt{{{}}} & {kind} & {old} & {new} & {page} & {status} \\\\\n", tex::escape(&row.label)));
+    }
+    out.push_str("\\hline\n\\end{tabular}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aux(labels: &[(&str, &str, Option<&str>)]) -> Aux {
+        let labels = labels.iter().map(|&(key, number, kind)| {
+            (key.to_string(), Label { number: number.to_string(), page: "1".to_string(), kind: kind.map(str::to_string) })
+        });
+        Aux { labels: labels.collect(), ..Aux::default() }
+    }
+
+    #[test]
+    fn orders_and_classifies_labels() {
+        let old = aux(&[("fig:a", "3", None), ("fig:gone", "1", None), ("eq:x", "2.9", None), ("misc", "1", Some("theorem"))]);
+        let new = aux(&[("fig:a", "4", None), ("fig:new", "1", None), ("eq:x", "2.10", None), ("eq:y", "2.9", None), ("misc", "1", Some("theorem"))]);
+        let rows = renumbering(&old, &new);
+        let rows: Vec<(&str, &str, Status)> = rows.iter().map(|r| (r.label.as_str(), r.kind.as_str(), r.status)).collect();
+        assert_eq!(
+            rows,
+            [
+                ("eq:y", "equation", Status::Added),
+                ("eq:x", "equation", Status::Renumbered),
+                ("fig:gone", "figure", Status::Removed),
+                ("fig:new", "figure", Status::Added),
+                ("fig:a", "figure", Status::Renumbered),
+                ("misc", "theorem", Status::Unchanged),
+            ]
+        );
+    }
+}
@@ -1,17 +1,42 @@
+mod archive;
+mod baseline;
+mod blame;
+mod changes;
+mod cites;
 mod cmd;
+mod count;
+mod demacro;
+mod difftex;
 pub mod error;
+mod labels;
+mod mask;
+mod noise;
 pub mod param;
+mod pdf;
+mod preamble;
+mod response;
+mod scope;
+mod series;
+mod sidebyside;
+#[cfg(test)]
+mod testing;
+mod tex;
+mod vcs;
+mod warnings;
 
 use crate::{
+    baseline::Baseline,
     cmd::latexmk::LaTeXMK,
+    difftex::DiffTex,
     error::{Error, Result},
-    param::{Opts, Param},
+    param::{Against, Opts, Param},
 };
 use bstr::io::BufReadExt as _;
 use clap::Parser;
 use std::{
     ffi::{OsStr, OsString},
     io::{BufReader, Write as _},
+    path::{Path, PathBuf},
     process::{Child, ChildStdout, Command, Stdio},
 };
 
@@ -24,8 +49,27 @@ fn main() {
 
 fn main2() -> error::Result<Option<i32>> {
     let param = Param::try_from(Opts::parse())?;
+    if let Some(series) = &param.series {
+        return series::run(&param, series);
+    }
+    if let Some(Against::Stash(stash)) = &param.against {
+        return series::stash(&param, stash);
+    }
+    if let Some(branch) = &param.branch_diff {
+        return series::branch(&param, branch);
+    }
     if param.diff_only {
-        return diffmk(&param);
+        let mut code = if unchanged(&param)? { Some(0) } else { diffmk(&param)? };
+        // `--async-diff`から呼ばれた場合は、メインのPDFを待てる呼び出し元が並べたり上限を確かめたりする
+        if !param.async_diff {
+            if param.side_by_side && param.outdir.join(osstr_join(&param.docfile, ".pdf")).exists() {
+                sidebyside::make(&param)?;
+            }
+            if count::exceeds_limits(&param) {
+                code = Some(count::LIMIT_EXCEEDED);
+            }
+        }
+        return Ok(diff_baselines(&param, code));
     }
     let latexmk = param.latexmk(); // 普通のlatexmk
     let (enable_typeset, mut latexmk_out, mut latexmk_spawn) = latexmk_and_sure_typeset(&latexmk)?;
@@ -35,7 +79,13 @@ fn main2() -> error::Result<Option<i32>> {
     if enable_typeset && !param.async_diff {
         // そうでない場合、別スレッドで標準出力だけ横流しする。エラーの出ようがないので無視。
         let _ = std::thread::spawn(move || std::io::copy(&mut latexmk_out, &mut stdout));
-        let diff_res = diffmk(&param); // latexdiff-vcからタイプセットまでを実行する
+        // 実質的な変更が無ければ差分は作らず、変更を元にする報告も出さない
+        // 比べる前の書き直しの失敗も、ほかの差分の失敗と同じくメインのビルドを待ってから扱う
+        let diff_res = match unchanged(&param) {
+            Ok(true) => Ok(None),
+            Ok(false) => diffmk(&param), // latexdiff-vcからタイプセットまでを実行する
+            Err(e) => Err(e),
+        };
         // diffmkは`*_diff`について作業(`*_diff.aux`などを生成)し、`mk`は`*`について作業する(`*.aux`などを生成する)ため、生成ファイルやその処理が全く被らないことに注意(関係ないファイルを上書きすることはあるが、実行時のエラーになるわけではない)
 
         // メインのlatexmkが成功しなかったらその場で失敗する
@@ -44,8 +94,26 @@ fn main2() -> error::Result<Option<i32>> {
             Err(Error::AlreadySaid)?;
         }
         latexmk.rename_pdf()?;
-        diff_res?;
-        return Ok(mk_status.code());
+        // 上限は新しい版だけで決まるので、差分を作らなかったり失敗したりしても確かめる
+        let exceeded = count::exceeds_limits(&param);
+        let mut reported = true;
+        match diff_res {
+            Ok(Some(0)) => reported = reports(&param),
+            Ok(_) => {}
+            Err(e) if exceeded => e.print(),
+            Err(e) => return Err(e),
+        }
+        if param.side_by_side {
+            sidebyside::make(&param)?;
+        }
+        let code = if exceeded {
+            Some(count::LIMIT_EXCEEDED)
+        } else if !reported {
+            Some(1)
+        } else {
+            mk_status.code()
+        };
+        return Ok(diff_baselines(&param, code));
     }
 
     // 非同期でdiffを取る場合の対応
@@ -65,10 +133,17 @@ fn main2() -> error::Result<Option<i32>> {
         Err(Error::AlreadySaid)?;
     }
     latexmk.rename_pdf()?;
+    if param.side_by_side {
+        // 非同期の場合も、メインのPDFが出来てから並べる
+        sidebyside::make(&param)?;
+    }
     if param.latexmk_opts.synctex {
         // この場合LaTeX WorkshopがSyncTeX位置反映を怠るので、擬似的に出力があったということにしておく
         println!("Output written on dummy.pdf (for LaTeX Workshop's SyncTeX refresh on {:?}).", param.docfile);
     }
+    if count::exceeds_limits(&param) {
+        return Ok(Some(count::LIMIT_EXCEEDED));
+    }
     Ok(mk_status.code())
 }
 
@@ -98,22 +173,201 @@ fn latexmk_and_sure_typeset(latexmk: &LaTeXMK) -> Result<(bool, ChildStdout, Chi
 }
 
 fn diffmk(param: &Param) -> error::Result<Option<i32>> {
-    // doc.texであればdoc_diff.texとかになる。
-    let latexdiff_vc = param.latexdiff_vc();
-    let latexdiff_vc_code = cmd_for_diff(latexdiff_vc.command(), param.diff_only)?;
-    latexdiff_vc.rename_tex()?;
-    if latexdiff_vc_code != Some(0) {
-        return Ok(latexdiff_vc_code);
+    let baseline = Baseline::from_param(param)?;
+    let latexdiff_code = match &baseline {
+        Some(baseline) if param.diffs_directly() => {
+            // インデックスや手元のファイルなどはlatexdiff-vcが扱えないので、取り出したものと直接比べる
+            let (old, new) = sources(param, baseline)?;
+            // 差分PDFの置き場所はlatexdiff-vcが作るものだったので、ここで作る
+            error::create_dir_all(&param.tmpdir)?;
+            error::create_dir_all(param.diff_outdir())?;
+            param.latexdiff(&old, &new).run(&param.diff_tex_path())?
+        }
+        _ => {
+            // doc.texであればdoc_diff.texとかになる。
+            let latexdiff_vc = param.latexdiff_vc();
+            let latexdiff_vc_code = cmd_for_diff(latexdiff_vc.command(), param.diff_only)?;
+            latexdiff_vc.rename_tex()?;
+            latexdiff_vc_code
+        }
+    };
+    if latexdiff_code != Some(0) {
+        return Ok(latexdiff_code);
+    }
+    let mut diff_tex = DiffTex::read(param.diff_tex_path())?;
+    // 古いリビジョンの画像などを取り出し、削除部分からはそちらを参照させる
+    if let Some(baseline) = &baseline {
+        let assets = baseline.extract_assets()?;
+        diff_tex.redirect_deleted_graphics(&assets.graphics);
+        // 古いリビジョンのビルドはハッシュごとに一度だけ行われる。失敗しても差分は作る
+        if (param.old_refs || param.with_old) && old_build_succeeded(baseline) {
+            if param.old_refs {
+                diff_tex.resolve_deleted_refs(&baseline.aux()?);
+            }
+            if param.with_old {
+                baseline.copy_pdf()?;
+            }
+        }
+        if param.preamble_page {
+            preamble::add_page(param, baseline, &mut diff_tex);
+        }
+    }
+    if param.marks_hunks() {
+        diff_tex.mark_hunks();
     }
+    if param.blame_notes.is_some() || param.color_by_author {
+        blame::apply(param, &mut diff_tex)?;
+    }
+    diff_tex.write()?;
 
     // ここでは一時的にparam.dir.join(DIFF_DIR_NAME)をちゃんと作成してそれを参照しているコードとして解釈されており、問題はない
     // 実際にダングリング参照になる場合はRustコンパイラが警告を出すが、今回はそうなっていない
     let latexmk = param.latexmk_for_diff();
     let latexmk_code = cmd_for_diff(latexmk.command()?, param.diff_only)?;
     latexmk.rename_pdf()?;
+    if param.latexdiffvc_opts.only_changes {
+        changes::changed_pages_pdf(param)?;
+    }
+    // 非同期の場合はメインのビルドを待てないので、その時点の結果で作る
+    if param.diff_only && latexmk_code == Some(0) && !reports(param) {
+        return Ok(Some(1));
+    }
     Ok(latexmk_code)
 }
 
+/// 古いリビジョンをビルドし、失敗すれば警告だけ出す(`--old-refs`や`--with-old`は諦めて差分は作る)
+fn old_build_succeeded(baseline: &Baseline) -> bool {
+    let failed = match baseline.build() {
+        Ok(Some(0)) => return true,
+        Ok(_) => None,
+        Err(e) => Some(e),
+    };
+    eprintln!("Warning: building the old revision ({}) failed; old numbers and the old PDF are skipped", baseline.param.old_revision());
+    if let Some(e) = failed {
+        e.print();
+    }
+    false
+}
+
+/// 直接latexdiffにかける古い側と新しい側のメインの`.tex`。`--only-paths`などの指定があれば、反映したものを一時ディレクトリに作る
+fn sources(param: &Param, baseline: &Baseline) -> Result<(PathBuf, PathBuf)> {
+    // `-r A -r B`なら作業ツリーではなく`B`と比べる
+    let new = match Baseline::new_side(param)? {
+        Some(new_side) => new_side.main_tex()?,
+        None => param.dir.join(osstr_join(&param.docfile, ".tex")),
+    };
+    prepare(param, baseline, new, &param.tmpdir)
+}
+
+/// 古い側は`baseline`、新しい側はメインの`.tex`が`new`の文書を、`--only-paths`や`--take-new`、`--expand-macros`、`--ignore-noise`に従って書き直し、`dir`の下に置く
+fn prepare(param: &Param, baseline: &Baseline, mut new: PathBuf, dir: &Path) -> Result<(PathBuf, PathBuf)> {
+    let mut old = match &param.scope {
+        Some(scope) => scope::old_tex(param, scope, baseline, &new, &dir.join("scoped-old"))?,
+        None => baseline.main_tex()?,
+    };
+    // 定義は手元の`.sty`がある書き直す前のツリーから読む
+    let macros = param.expand_macros.then(|| {
+        let read = |main: &Path| demacro::Macros::read(main, &param.dir, &param.keep_macros);
+        (read(&old), read(&new))
+    });
+    if let Some(mask) = &param.mask {
+        (old, new) = mask.apply(&old, &new, &dir.join("masked"))?;
+    }
+    if let Some(macros) = &macros {
+        (old, new) = demacro::apply(macros, &old, &new, &dir.join("expanded"))?;
+    }
+    if param.ignore_noise {
+        (old, new) = noise::apply(&old, &new, &dir.join("normalized"))?;
+    }
+    Ok((old, new))
+}
+
+/// `--ignore-noise`で、コメントや空白、改行位置のほかに変更が無いか。無ければそう表示する
+fn unchanged(param: &Param) -> Result<bool> {
+    if !param.ignore_noise {
+        return Ok(false);
+    }
+    let Some(baseline) = Baseline::from_param(param)? else { return Ok(false) };
+    let (old, new) = sources(param, &baseline)?;
+    let same = noise::same(&old, &new);
+    if same {
+        println!("No substantive changes vs {}", param.old_revision());
+    }
+    Ok(same)
+}
+
+/// メインと差分の両方のビルド結果から作るもの。失敗したものはエラーを表示して残りを続け、すべて成功すれば`true`
+fn reports(param: &Param) -> bool {
+    let mut ok = true;
+    let mut run = |enabled: bool, report: &dyn Fn() -> Result<()>| {
+        if enabled && let Err(e) = report() {
+            e.print();
+            ok = false;
+        }
+    };
+    run(param.change_index, &|| changes::write_index(param));
+    run(param.summary, &|| changes::print_summary(param));
+    run(param.new_warnings, &|| warnings::report(param));
+    if let Some(format) = param.label_map {
+        run(true, &|| labels::write(param, format));
+    }
+    run(param.cite_report, &|| cites::report(param));
+    run(param.response, &|| response::write(param));
+    run(param.word_count, &|| count::report(param));
+    ok
+}
+
+/// `--baseline`ごとの差分を並行して作る。失敗は表示だけして、終了コードを1にする
+fn diff_baselines(param: &Param, code: Option<i32>) -> Option<i32> {
+    let git = vcs::git::Git { dir: &param.dir };
+    let results = in_parallel(param.jobs, &param.baselines, |baseline| {
+        let revision = match baseline.revision.strip_prefix("@tag:") {
+            Some(pattern) => git.tags(pattern)?.pop().ok_or_else(|| Error::NoMatchingTag(pattern.to_string()))?,
+            None => baseline.revision.clone(),
+        };
+        let param = param.for_baseline(baseline, revision);
+        if unchanged(&param)? {
+            return Ok(Some(0));
+        }
+        diffmk(&param)
+    });
+    let mut failed = false;
+    for (baseline, result) in param.baselines.iter().zip(results) {
+        match result {
+            Ok(Some(0)) => continue,
+            Ok(_) => eprintln!("Diff against baseline \"{}\" failed", baseline.name),
+            Err(e) => {
+                eprintln!("Diff against baseline \"{}\" failed:", baseline.name);
+                e.print();
+            }
+        }
+        failed = true;
+    }
+    if failed && code == Some(0) { Some(1) } else { code }
+}
+
+/// `items`それぞれについて`f`を高々`jobs`個ずつ並行に実行し、結果を`items`の順に返す
+fn in_parallel<T: Sync, R: Send>(jobs: usize, items: &[T], f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let next = std::sync::atomic::AtomicUsize::new(0);
+    let mut results: Vec<(usize, R)> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.min(items.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let n = next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        let Some(item) = items.get(n) else { return done };
+                        done.push((n, f(item)));
+                    }
+                })
+            })
+            .collect();
+        workers.into_iter().flat_map(|worker| worker.join().expect("worker panicked")).collect()
+    });
+    results.sort_by_key(|(n, _)| *n);
+    results.into_iter().map(|(_, r)| r).collect()
+}
+
 fn cmd_for_diff(mut cmd: Command, diff_only: bool) -> Result<Option<i32>> {
     if diff_only {
         let output = cmd.output().map_err(Error::CommandFailed)?;
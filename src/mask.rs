@@ -0,0 +1,262 @@
+//! latexdiffにかける前に、差分の印を付けたくない部分を両方の版で揃える。
+//! `--take-new`は古い側の該当部分を新しい側のもので置き換え(差分が付かず今の文が出る)、`--strip`は両方から取り除く。
+//! 対象は環境(`env:tabular`)、コマンドとその引数(`macro:todo`)、あるコマンドから文書の終わりまで(`from:appendix`)。
+//! 環境とコマンドは、同じ名前のもの同士を現れる順に組にする。新しい側にしか無いものは、前にある文を手がかりに古い側にも足す。
+
+use std::{
+    collections::BTreeSet,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use crate::{error, tex};
+
+/// 揃える部分の指定
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Region {
+    Env(String),
+    Macro(String),
+    /// このコマンドから`\end{document}`(無ければファイルの終わり)まで。後から取り込まれるファイルも含む
+    From(String),
+}
+
+pub fn parse_region(s: &str) -> Result<Region, String> {
+    let (kind, name) = s.split_once(':').ok_or_else(|| format!("expected env:NAME, macro:NAME or from:NAME, got \"{s}\""))?;
+    let name = name.trim_start_matches('\\').to_string();
+    if name.is_empty() {
+        return Err(format!("no name in \"{s}\""));
+    }
+    match kind {
+        "env" => Ok(Region::Env(name)),
+        "macro" => Ok(Region::Macro(name)),
+        "from" => Ok(Region::From(name)),
+        _ => Err(format!("unknown region kind \"{kind}\" (expected env, macro or from)")),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    TakeNew,
+    Strip,
+}
+
+#[derive(Debug, Clone)]
+pub struct Mask {
+    rules: Vec<(Region, Action)>,
+}
+impl Mask {
+    /// どちらも指定されていなければ`None`
+    pub fn new(take_new: Vec<Region>, strip: Vec<Region>) -> Option<Mask> {
+        let rules: Vec<_> = take_new
+            .into_iter()
+            .map(|region| (region, Action::TakeNew))
+            .chain(strip.into_iter().map(|region| (region, Action::Strip)))
+            .collect();
+        (!rules.is_empty()).then_some(Mask { rules })
+    }
+
+    /// `old`と`new`(どちらもメインの`.tex`)から取り込まれる`.tex`を揃えて`dir/old`と`dir/new`に書き、それぞれのメインの`.tex`を返す
+    pub fn apply(&self, old: &Path, new: &Path, dir: &Path) -> error::Result<(PathBuf, PathBuf)> {
+        error::remove_dir_all(dir)?;
+        let (old_dir, new_dir) = (old.parent().unwrap_or(Path::new(".")), new.parent().unwrap_or(Path::new(".")));
+        let (old_main, new_main) = (PathBuf::from(old.file_name().unwrap_or_default()), PathBuf::from(new.file_name().unwrap_or_default()));
+        let old_flat = tex::flatten(&old_main, &mut tex::read_from_dir(old_dir));
+        let new_flat = tex::flatten(&new_main, &mut tex::read_from_dir(new_dir));
+        // `from:`より後に取り込まれるファイルは、中身全体がその範囲に入る
+        let after: Vec<(BTreeSet<PathBuf>, BTreeSet<PathBuf>, Action)> = self
+            .rules
+            .iter()
+            .filter_map(|(region, action)| match region {
+                Region::From(name) => Some((files_after(&old_flat, name), files_after(&new_flat, name), *action)),
+                _ => None,
+            })
+            .collect();
+
+        let read = |dir: &Path, file: &Path| std::fs::read(dir.join(file)).ok().map(|b| String::from_utf8_lossy(&b).into_owned());
+        // メインの`.tex`の名前が両側で違うこともあるので、新しい側の名前で組にする
+        let old_name = |file: &PathBuf| if *file == old_main { new_main.clone() } else { file.clone() };
+        let files: BTreeSet<PathBuf> = old_flat.files.iter().map(old_name).chain(new_flat.files.iter().cloned()).collect();
+        for file in &files {
+            let old_file = if *file == new_main { &old_main } else { file };
+            let (mut old_src, mut new_src) = (read(old_dir, old_file), read(new_dir, file));
+            for (in_old, in_new, action) in &after {
+                if !in_new.contains(file) && !in_old.contains(old_file) {
+                    continue;
+                }
+                match action {
+                    Action::TakeNew => old_src.clone_from(&new_src),
+                    Action::Strip => {
+                        // 取り込みの記述は残るので、中身だけ空にする
+                        old_src = old_src.map(|_| String::new());
+                        new_src = new_src.map(|_| String::new());
+                    }
+                }
+            }
+            if let (Some(old_src), Some(new_src)) = (&mut old_src, &mut new_src) {
+                for (region, action) in &self.rules {
+                    (*old_src, *new_src) = apply_region(region, *action, old_src, new_src);
+                }
+            }
+            if let Some(src) = old_src {
+                write(&dir.join("old").join(file), src)?;
+            }
+            if let Some(src) = new_src {
+                write(&dir.join("new").join(file), src)?;
+            }
+        }
+        Ok((dir.join("old").join(&new_main), dir.join("new").join(&new_main)))
+    }
+}
+
+fn write(path: &Path, src: String) -> error::Result<()> {
+    error::create_dir_all(path.parent().unwrap_or(Path::new(".")))?;
+    error::write(path, src)
+}
+
+/// `\name`のある行より後に、別のファイルとして取り込まれるファイル
+fn files_after(flat: &tex::Flattened, name: &str) -> BTreeSet<PathBuf> {
+    let Some(at) = flat.lines.iter().position(|line| !tex::commands(tex::strip_comment(&line.text), &[name], 0).is_empty()) else {
+        return BTreeSet::new();
+    };
+    let file = &flat.lines[at].file;
+    flat.lines[at..].iter().map(|line| &line.file).filter(|f| *f != file).cloned().collect()
+}
+
+fn apply_region(region: &Region, action: Action, old: &str, new: &str) -> (String, String) {
+    let (old_ranges, new_ranges) = (ranges(region, old), ranges(region, new));
+    match action {
+        Action::Strip => (cut(old, &old_ranges, |_| ""), cut(new, &new_ranges, |_| "")),
+        Action::TakeNew => (take_new(region, old, &old_ranges, new, &new_ranges), new.to_string()),
+    }
+}
+
+/// 古い側の`region`に当たる部分を、新しい側のものに置き換える。
+/// 組になる相手が無い古い側の部分は消し(新しい側で消えたものに削除の印を付けない)、
+/// 古い側より多い新しい側の部分は同じ場所に足す(追加の印を付けない)
+fn take_new(region: &Region, old: &str, old_ranges: &[Range<usize>], new: &str, new_ranges: &[Range<usize>]) -> String {
+    let mut out = String::with_capacity(old.len());
+    let mut last = 0;
+    for (n, range) in old_ranges.iter().enumerate() {
+        out.push_str(&old[last..range.start]);
+        out.push_str(new_ranges.get(n).map_or("", |r| &new[r.clone()]));
+        last = range.end;
+    }
+    // 足す場所は、直前の組より後ろで、新しい側でその部分の前にある行が古い側にも見つかったところ(近い行から探す)
+    let mut cursor = out.len();
+    out.push_str(&old[last..]);
+    for n in old_ranges.len()..new_ranges.len() {
+        let range = &new_ranges[n];
+        let at = match region {
+            // 新しく付録を始めた場合なども、古い側の終わりに足す
+            Region::From(_) => document_end(&out),
+            _ => {
+                let before = &new[n.checked_sub(1).map_or(0, |p| new_ranges[p].end)..range.start];
+                let mut lines = before.lines().rev().map(str::trim).filter(|line| !line.is_empty());
+                match lines.find_map(|line| out[cursor..].find(line).map(|p| cursor + p + line.len())) {
+                    Some(at) => at,
+                    None if n > 0 => cursor,
+                    None => document_end(&out),
+                }
+            }
+        };
+        out.insert_str(at, &new[range.clone()]);
+        cursor = at + range.len();
+    }
+    out
+}
+
+/// `ranges`の部分を、n番目なら`with(n)`に置き換える
+fn cut<'a>(src: &str, ranges: &[Range<usize>], with: impl Fn(usize) -> &'a str) -> String {
+    let mut out = String::with_capacity(src.len());
+    let mut last = 0;
+    for (n, range) in ranges.iter().enumerate() {
+        out.push_str(&src[last..range.start]);
+        out.push_str(with(n));
+        last = range.end;
+    }
+    out.push_str(&src[last..]);
+    out
+}
+
+/// `src`の中で`region`に当たる部分。入れ子になったものは外側だけ
+fn ranges(region: &Region, src: &str) -> Vec<Range<usize>> {
+    match region {
+        Region::Macro(name) => tex::commands(src, &[name.as_str()], 1).into_iter().map(|cmd| cmd.start..cmd.end).collect(),
+        Region::Env(name) => {
+            let mut found = Vec::new();
+            let mut depth = 0;
+            let mut start = 0;
+            for cmd in tex::commands(src, &["begin", "end"], 1).into_iter().filter(|cmd| cmd.args[0].trim() == name) {
+                match (cmd.name, depth) {
+                    ("begin", 0) => {
+                        start = cmd.start;
+                        depth = 1;
+                    }
+                    ("begin", _) => depth += 1,
+                    ("end", 0) => {}
+                    ("end", 1) => {
+                        found.push(start..cmd.end);
+                        depth = 0;
+                    }
+                    _ => depth -= 1,
+                }
+            }
+            found
+        }
+        Region::From(name) => {
+            let Some(cmd) = tex::commands(src, &[name.as_str()], 0).into_iter().next() else { return Vec::new() };
+            let end = cmd.start + document_end(&src[cmd.start..]);
+            std::iter::once(cmd.start..end).collect()
+        }
+    }
+}
+
+/// `\end{document}`の位置。無ければ終わり
+fn document_end(src: &str) -> usize {
+    let ends = tex::commands(src, &["end"], 1);
+    ends.into_iter().find(|end| end.args[0].trim() == "document").map_or(src.len(), |end| end.start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_regions() {
+        assert_eq!(parse_region("env:tabular"), Ok(Region::Env("tabular".to_string())));
+        assert_eq!(parse_region("macro:\\todo"), Ok(Region::Macro("todo".to_string())));
+        assert_eq!(parse_region("from:appendix"), Ok(Region::From("appendix".to_string())));
+        assert!(parse_region("todo").is_err());
+        assert!(parse_region("env:").is_err());
+        assert!(parse_region("box:x").is_err());
+    }
+
+    #[test]
+    fn strips_outer_environments_on_both_sides() {
+        let region = Region::Env("table".to_string());
+        let (old, new) = apply_region(&region, Action::Strip, "a\\begin{table}x\\begin{table}y\\end{table}\\end{table}b", "a\\begin{table}z\\end{table}b");
+        assert_eq!((old.as_str(), new.as_str()), ("ab", "ab"));
+    }
+
+    #[test]
+    fn takes_every_new_occurrence() {
+        let region = Region::Macro("todo".to_string());
+        let old = "\\begin{document}\nFirst \\todo{a} here.\nSecond line.\n\\end{document}\n";
+        let new = "\\begin{document}\nFirst \\todo{A} here.\nSecond \\todo{B} line.\nThird \\todo{C}\n\\end{document}\n";
+        let (masked, _) = apply_region(&region, Action::TakeNew, old, new);
+        assert_eq!(masked, "\\begin{document}\nFirst \\todo{A} here.\nSecond\\todo{B} line.\\todo{C}\n\\end{document}\n");
+        // 古い側にしか無いものは消える
+        let (masked, _) = apply_region(&region, Action::TakeNew, new, old);
+        assert_eq!(masked, "\\begin{document}\nFirst \\todo{a} here.\nSecond  line.\nThird \n\\end{document}\n");
+        // 古い側に一つも無ければ、前の行を手がかりにする
+        let (masked, _) = apply_region(&region, Action::TakeNew, "\\begin{document}\nA.\nB.\n\\end{document}", "\\begin{document}\nA.\n\\todo{x}B.\n\\end{document}");
+        assert_eq!(masked, "\\begin{document}\nA.\\todo{x}\nB.\n\\end{document}");
+    }
+
+    #[test]
+    fn takes_a_new_appendix_at_the_end() {
+        let region = Region::From("appendix".to_string());
+        let (masked, _) = apply_region(&region, Action::TakeNew, "A\n\\end{document}", "A\n\\appendix B\n\\end{document}");
+        assert_eq!(masked, "A\n\\appendix B\n\\end{document}");
+    }
+}
@@ -0,0 +1,114 @@
+//! `--ignore-noise`。コメントを消し、空白をまとめ、段落を一行にしてから比べる。
+//! 段落の詰め直しやコメントの書き換えだけなら差分を作らず、変わったところがあっても印は実質的な変更にだけ付く。
+
+use std::path::{Path, PathBuf};
+
+use crate::{error, tex};
+
+/// TeXが同じに読むソースは同じ文字列になるように書き直す。
+/// 本文は空行で区切られた段落を一行にまとめ、コメントは行末の改行ごと消す
+pub fn normalize(src: &str) -> String {
+    let mut out = String::with_capacity(src.len());
+    let mut para = String::new();
+    let flush = |out: &mut String, para: &mut String| {
+        let text = tex::collapse_ws(para);
+        if !text.is_empty() {
+            out.push_str(&text);
+            out.push_str("\n\n");
+        }
+        para.clear();
+    };
+    let mut verbatim: Option<String> = None;
+    // プリアンブルは行ごとに読まれるので、コメントと前後の空白を消すだけにする
+    let mut preamble = src.contains("\\begin{document}");
+    for line in src.lines() {
+        if preamble {
+            let code = tex::strip_comment(line).trim();
+            preamble = !code.contains("\\begin{document}");
+            if !code.is_empty() {
+                out.push_str(code);
+                out.push('\n');
+            }
+            continue;
+        }
+        if let Some(end) = &verbatim {
+            out.push_str(line);
+            out.push('\n');
+            if line.contains(end.as_str()) {
+                verbatim = None;
+            }
+            continue;
+        }
+        let code = tex::strip_comment(line);
+        if let Some(env) = tex::VERBATIM_ENVS.iter().find(|env| code.contains(&format!("\\begin{{{env}}}"))) {
+            flush(&mut out, &mut para);
+            out.push_str(line);
+            out.push('\n');
+            let end = format!("\\end{{{env}}}");
+            if !line.contains(&end) {
+                verbatim = Some(end);
+            }
+            continue;
+        }
+        let commented = code.len() < line.len();
+        if !commented && code.trim().is_empty() {
+            flush(&mut out, &mut para);
+            continue;
+        }
+        para.push_str(code);
+        // コメントで終わる行は改行が空白にならない
+        if !commented {
+            para.push(' ');
+        }
+    }
+    flush(&mut out, &mut para);
+    out
+}
+
+/// `old`と`new`(どちらもメインの`.tex`)から取り込まれる`.tex`を書き直して`dir/old`と`dir/new`に置き、それぞれのメインの`.tex`を返す
+pub fn apply(old: &Path, new: &Path, dir: &Path) -> error::Result<(PathBuf, PathBuf)> {
+    error::remove_dir_all(dir)?;
+    Ok((rewrite(old, &dir.join("old"))?, rewrite(new, &dir.join("new"))?))
+}
+
+fn rewrite(main: &Path, dest: &Path) -> error::Result<PathBuf> {
+    let src_dir = main.parent().unwrap_or(Path::new("."));
+    let name = PathBuf::from(main.file_name().unwrap_or_default());
+    let mut read = tex::read_from_dir(src_dir);
+    for file in tex::flatten(&name, &mut read).files {
+        let Some(src) = read(&file) else { continue };
+        let path = dest.join(&file);
+        error::create_dir_all(path.parent().unwrap_or(dest))?;
+        error::write(path, normalize(&src))?;
+    }
+    Ok(dest.join(name))
+}
+
+/// 取り込まれるファイルまで含めて同じ文書か
+pub fn same(old: &Path, new: &Path) -> bool {
+    let text = |main: &Path| {
+        let dir = main.parent().unwrap_or(Path::new("."));
+        tex::flatten(Path::new(main.file_name().unwrap_or_default()), &mut tex::read_from_dir(dir)).text()
+    };
+    text(old) == text(new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn joins_paragraphs_and_drops_comments() {
+        let src = "\\documentclass{article} % class\n\n  \\usepackage{x}\n\\begin{document}\nOne  line\nwrapped. % note\nOn%\nce.\n\n\n% only a comment\nNext.\n\\end{document}\n";
+        assert_eq!(normalize(src), "\\documentclass{article}\n\\usepackage{x}\n\\begin{document}\nOne line wrapped. Once.\n\nNext. \\end{document}\n\n");
+        // 詰め直しやコメントの書き換えだけなら同じになる
+        let rewrapped = "\\documentclass{article}\n\\usepackage{x}\n\\begin{document}\nOne line wrapped.\nOn%other\nce.\n\nNext.\n\\end{document}\n";
+        assert_eq!(normalize(src), normalize(rewrapped));
+    }
+
+    #[test]
+    fn keeps_verbatim_as_is() {
+        let src = "A\nb.\n\\begin{verbatim}\nx  % y\n\n  z\n\\end{verbatim}\nC.\n";
+        assert_eq!(normalize(src), "A b.\n\n\\begin{verbatim}\nx  % y\n\n  z\n\\end{verbatim}\nC.\n\n");
+    }
+}
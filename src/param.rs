@@ -4,14 +4,23 @@ use std::{
 };
 
 use crate::{
+    blame,
     cmd::{
-        latexdiff,
+        latexdiff::{self, Latexdiff},
         latexdiff_vc::{self, LatexdiffVc},
         latexmk::{self, LaTeXMK},
     },
-    error::{self, Error::CurrentDirFailed},
+    error::{
+        self,
+        Error::{CurrentDirFailed, TooManyRevisions},
+    },
+    labels,
+    mask::{self, Mask, Region},
     osstr_join,
+    scope::Scope,
+    sidebyside,
 };
+use globset::Glob;
 
 #[derive(clap::Parser, Debug)]
 pub struct Opts {
@@ -33,6 +42,9 @@ pub struct Opts {
     /// Path to latexdiff-vc executable [default: "latexdiff-vc"]
     #[clap(long, value_parser)]
     latexdiff_vc: Option<PathBuf>,
+    /// Path to latexdiff executable, used when diffing extracted trees directly [default: "latexdiff"]
+    #[clap(long, value_parser)]
+    latexdiff: Option<PathBuf>,
 
     /// Only generate diff output
     #[clap(long, value_parser, hide = true)]
@@ -42,6 +54,104 @@ pub struct Opts {
     #[clap(long, value_parser)]
     async_diff: bool,
 
+    /// Show original numbers for \ref and \cite in deleted text (builds the old revision)
+    #[clap(long, value_parser)]
+    old_refs: bool,
+    /// Also write the old revision as "<doc>-old.pdf" next to the diff PDF
+    #[clap(long, value_parser)]
+    with_old: bool,
+    /// Also write "<doc>-sidebyside.pdf" with old and new pages next to each other, aligned by section
+    #[clap(long, value_parser)]
+    side_by_side: bool,
+    /// Also write "<doc>-diff.changes.json" listing each change with its source lines, section and pages
+    #[clap(long, value_parser)]
+    change_index: bool,
+    /// Print which sections contain changes and how many words were added and removed in each
+    #[clap(long, value_parser)]
+    summary: bool,
+    /// Report warnings (undefined references and citations, overfull boxes, ...) that are new since the old revision
+    #[clap(long, value_parser)]
+    new_warnings: bool,
+    /// Write "<doc>-labels.<md|json|tex>" mapping each label's old number and page to the new ones
+    #[clap(long, value_enum)]
+    label_map: Option<labels::Format>,
+    /// Write "<doc>-citations.md" listing citations and .bib entries added, removed or modified since the old revision
+    #[clap(long, value_parser)]
+    cite_report: bool,
+    /// Write "<doc>-response.tex" and "<doc>-response.md", a response letter skeleton quoting each change
+    #[clap(long, value_parser)]
+    response: bool,
+    /// YAML list of reviewer comments ({reviewer, id, comment, labels, search}) to link to changes (implies --response)
+    #[clap(long, value_parser)]
+    reviewer_comments: Option<PathBuf>,
+    /// Annotate each addition with the commit, author and subject that last changed it, as margin notes or PDF tooltips
+    #[clap(long, value_enum)]
+    blame_notes: Option<blame::NoteStyle>,
+    /// Color added text by the git author who last changed each line, with a legend on the first page
+    #[clap(long, value_parser)]
+    color_by_author: bool,
+    /// Print word counts (text, captions, footnotes) and page counts of the old and new revisions
+    #[clap(long, value_parser)]
+    word_count: bool,
+    /// Exit with code 3 if the text of the new revision has more words than this (implies --word-count)
+    #[clap(long, value_parser)]
+    max_words: Option<usize>,
+    /// Exit with code 3 if the new revision has more pages than this (implies --word-count)
+    #[clap(long, value_parser)]
+    max_pages: Option<usize>,
+
+    /// Instead of the usual build, write a diff PDF for every consecutive pair of commits in a git range (e.g. "v1..HEAD"; a single REV means REV..HEAD)
+    #[clap(long, value_parser = parse_range, conflicts_with_all = ["series_tags", "hg", "jj"])]
+    series: Option<String>,
+    /// Like --series, but for the tags matching a pattern (e.g. "submitted/*"), oldest first
+    #[clap(long, value_parser, conflicts_with_all = ["hg", "jj"])]
+    series_tags: Option<String>,
+    /// Diff against "index" (staged content, so only unstaged edits are marked) or show what "stash@{n}" changes
+    #[clap(long, value_parser = parse_against, conflicts_with_all = ["revision", "series", "series_tags", "hg", "jj"])]
+    against: Option<Against>,
+    /// Diff against a plain .tex file instead of a revision (runs latexdiff directly, bypassing latexdiff-vc)
+    #[clap(long, value_parser, group = "old_source", conflicts_with_all = ["revision", "against"])]
+    old_file: Option<PathBuf>,
+    /// Diff against the document of the same name in a directory instead of a revision
+    #[clap(long, value_parser, group = "old_source", conflicts_with_all = ["revision", "against"])]
+    old_dir: Option<PathBuf>,
+    /// Diff against the document in a .zip, .tar.gz, .tgz or .tar archive (e.g. the accepted manuscript)
+    #[clap(long, value_parser, group = "old_source", conflicts_with_all = ["revision", "against"])]
+    old_archive: Option<PathBuf>,
+    /// Only mark changes in files matching GLOB (relative to the document, repeatable; implies --flatten); other files show their current text
+    #[clap(long, value_parser = parse_glob)]
+    only_paths: Vec<Glob>,
+    /// Do not mark changes in files matching GLOB (repeatable); they show their current text
+    #[clap(long, value_parser = parse_glob)]
+    exclude_paths: Vec<Glob>,
+    /// Show REGION (env:NAME, macro:NAME or from:NAME, e.g. from:appendix) as in the new version without markup; repeatable, implies --flatten
+    #[clap(long, value_parser = mask::parse_region)]
+    take_new: Vec<Region>,
+    /// Remove REGION (env:NAME, macro:NAME or from:NAME, e.g. macro:todo) from both versions before diffing; repeatable, implies --flatten
+    #[clap(long, value_parser = mask::parse_region)]
+    strip: Vec<Region>,
+    /// Ignore comment, whitespace and line-wrapping changes; skip the diff build if nothing else changed; implies --flatten
+    #[clap(long, value_parser)]
+    ignore_noise: bool,
+    /// Start the diff PDF with a page listing class, package and macro definition changes in the preamble
+    #[clap(long, value_parser)]
+    preamble_page: bool,
+    /// Expand simple macros defined in the preamble and local .sty files in both versions before diffing; implies --flatten
+    #[clap(long, value_parser)]
+    expand_macros: bool,
+    /// Leave macro NAME unexpanded with --expand-macros; repeatable
+    #[clap(long, value_parser, requires = "expand_macros")]
+    keep_macro: Vec<String>,
+    /// Instead of the usual build, write "<doc>-diff-<branch>.pdf" with what a branch changed since it forked from HEAD
+    #[clap(long, value_parser, conflicts_with_all = ["series", "series_tags", "against", "hg", "jj"])]
+    branch_diff: Option<String>,
+    /// Also diff against another revision, as NAME=REV ("@tag:PATTERN" picks the newest matching tag); repeatable
+    #[clap(long, value_parser = parse_baseline)]
+    baseline: Vec<NamedBaseline>,
+    /// Maximum number of diffs typeset at the same time [default: number of CPUs]
+    #[clap(long, short, value_parser)]
+    jobs: Option<usize>,
+
     /// Name of subdirectory for diff output [default: "diff"]
     #[clap(long, short, value_parser)]
     diff_name: Option<String>,
@@ -56,6 +166,78 @@ pub struct Opts {
     #[clap(flatten)]
     latexdiffvc_ops: latexdiff_vc::Opts,
 }
+/// `--baseline NAME=REV`。メインの差分とは別に、`diff-NAME/<doc>-diff-NAME.pdf`を作る
+#[derive(Debug, Clone)]
+pub struct NamedBaseline {
+    pub name: String,
+    pub revision: String,
+}
+fn parse_baseline(s: &str) -> Result<NamedBaseline, String> {
+    let (name, revision) = s.split_once('=').ok_or_else(|| format!("expected NAME=REV, got \"{s}\""))?;
+    // 名前はディレクトリ名とファイル名に使う
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+        return Err(format!("baseline name \"{name}\" must be letters, digits, '-' or '_'"));
+    }
+    Ok(NamedBaseline { name: name.to_string(), revision: revision.to_string() })
+}
+
+/// `--against`。gitのリビジョン以外の比較元
+#[derive(Debug, Clone)]
+pub enum Against {
+    /// インデックスと作業ツリーを比べる
+    Index,
+    /// stashの元になったコミットとstashの内容を比べる
+    Stash(String),
+}
+fn parse_against(s: &str) -> Result<Against, String> {
+    match s {
+        "index" => Ok(Against::Index),
+        "stash" => Ok(Against::Stash("stash@{0}".to_string())),
+        _ if s.starts_with("stash@{") && s.ends_with('}') => Ok(Against::Stash(s.to_string())),
+        _ => Err(format!("expected \"index\" or \"stash@{{n}}\", got \"{s}\"")),
+    }
+}
+
+/// `--old-file`、`--old-dir`、`--old-archive`。バージョン管理の外にある比較元
+#[derive(Debug, Clone)]
+pub enum OldSource {
+    File(PathBuf),
+    Dir(PathBuf),
+    Archive(PathBuf),
+}
+impl OldSource {
+    pub fn path(&self) -> &Path {
+        match self {
+            OldSource::File(path) | OldSource::Dir(path) | OldSource::Archive(path) => path,
+        }
+    }
+}
+
+fn parse_glob(s: &str) -> Result<Glob, String> {
+    Glob::new(s).map_err(|e| e.to_string())
+}
+
+/// `A..B`の形の範囲。片方が無ければ`HEAD`とし、一つのリビジョン`REV`は`REV..HEAD`とする
+fn parse_range(s: &str) -> Result<String, String> {
+    let (from, to) = s.split_once("..").unwrap_or((s, ""));
+    if to.starts_with('.') {
+        return Err(format!("symmetric ranges like \"{s}\" are not supported; use A..B"));
+    }
+    if from.is_empty() && to.is_empty() {
+        return Err(format!("expected a range like v1..HEAD, got \"{s}\""));
+    }
+    let or_head = |rev: &str| if rev.is_empty() { "HEAD".to_string() } else { rev.to_string() };
+    Ok(format!("{}..{}", or_head(from), or_head(to)))
+}
+
+/// `--series`と`--series-tags`のどちらで並べるか
+#[derive(Debug, Clone)]
+pub enum Series {
+    Range(String),
+    Tags(String),
+}
+
+#[derive(Clone)]
 pub struct Param {
     pub dir: PathBuf,      // docの親ディレクトリ
     pub docfile: OsString, // file_stemに相当。拡張子は含まないし、ディレクトリも含まない
@@ -65,9 +247,38 @@ pub struct Param {
 
     pub async_diff: bool,
     pub diff_only: bool,
+    pub old_refs: bool,
+    pub with_old: bool,
+    pub side_by_side: bool,
+    pub change_index: bool,
+    pub summary: bool,
+    pub new_warnings: bool,
+    pub label_map: Option<labels::Format>,
+    pub cite_report: bool,
+    pub response: bool,
+    pub reviewer_comments: Option<PathBuf>,
+    pub blame_notes: Option<blame::NoteStyle>,
+    pub color_by_author: bool,
+    pub word_count: bool,
+    pub max_words: Option<usize>,
+    pub max_pages: Option<usize>,
+    pub series: Option<Series>,
+    pub against: Option<Against>,
+    pub branch_diff: Option<String>,
+    pub old_source: Option<OldSource>,
+    pub scope: Option<Scope>,
+    pub mask: Option<Mask>,
+    pub ignore_noise: bool,
+    pub preamble_page: bool,
+    pub expand_macros: bool,
+    /// `\`を除いた名前
+    pub keep_macros: Vec<String>,
+    pub baselines: Vec<NamedBaseline>,
+    pub jobs: usize,
 
     pub latexmk: PathBuf,
     pub latexdiff_vc: PathBuf,
+    pub latexdiff: PathBuf,
 
     pub diff_docfile: OsString,
     pub diff_dir_name: String,
@@ -113,7 +324,17 @@ impl TryFrom<Opts> for Param {
         };
         let latexmk = from.latexmk.unwrap_or_else(|| "latexmk".into());
         let latexdiff_vc = from.latexdiff_vc.unwrap_or_else(|| "latexdiff-vc".into());
-        Ok(Param {
+        let latexdiff = from.latexdiff.unwrap_or_else(|| "latexdiff".into());
+        let series = from.series.map(Series::Range).or(from.series_tags.map(Series::Tags));
+        // 比較元のパスは絶対パスにしておく(ツリーの中でコマンドを実行するため)
+        let old_source = match (from.old_file, from.old_dir, from.old_archive) {
+            (Some(p), _, _) => Some(OldSource::File(error::canonicalize(p)?)),
+            (_, Some(p), _) => Some(OldSource::Dir(error::canonicalize(p)?)),
+            (_, _, Some(p)) => Some(OldSource::Archive(error::canonicalize(p)?)),
+            _ => None,
+        };
+        let jobs = from.jobs.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get())).max(1);
+        let param = Param {
             dir,
             diff_docfile,
             docfile,
@@ -122,27 +343,151 @@ impl TryFrom<Opts> for Param {
             diff_dir_name,
             latexmk,
             latexdiff_vc,
+            latexdiff,
             async_diff: from.async_diff,
             diff_only: from.diff_only,
+            old_refs: from.old_refs,
+            with_old: from.with_old,
+            side_by_side: from.side_by_side,
+            change_index: from.change_index,
+            summary: from.summary,
+            new_warnings: from.new_warnings,
+            label_map: from.label_map,
+            cite_report: from.cite_report,
+            response: from.response || from.reviewer_comments.is_some(),
+            reviewer_comments: from.reviewer_comments,
+            blame_notes: from.blame_notes,
+            color_by_author: from.color_by_author,
+            word_count: from.word_count || from.max_words.is_some() || from.max_pages.is_some(),
+            max_words: from.max_words,
+            max_pages: from.max_pages,
+            series,
+            against: from.against,
+            branch_diff: from.branch_diff,
+            old_source,
+            scope: Scope::new(from.only_paths, from.exclude_paths)?,
+            mask: Mask::new(from.take_new, from.strip),
+            ignore_noise: from.ignore_noise,
+            preamble_page: from.preamble_page,
+            expand_macros: from.expand_macros,
+            keep_macros: from.keep_macro.iter().map(|name| name.trim_start_matches('\\').to_string()).collect(),
+            baselines: from.baseline,
+            jobs,
             latexmk_opts: from.latexmk_opts,
             latexdiff_opts: from.latexdiff_opts,
             latexdiffvc_opts: from.latexdiffvc_ops,
-        })
+        };
+        // latexdiff-vcは三つ以上のリビジョンを順に比べるが、直接比べる場合は古い側と新しい側の二つだけ
+        if param.diffs_directly() && param.latexdiffvc_opts.revision.len() > 2 {
+            return Err(TooManyRevisions(param.latexdiffvc_opts.revision.len()));
+        }
+        Ok(param)
     }
 }
 impl Param {
-    pub fn latexmk(&self) -> LaTeXMK {
+    pub fn latexmk(&self) -> LaTeXMK<'_> {
         let Param { latexmk, dir, docfile, tmpdir, outdir, latexmk_opts, .. } = self;
-        LaTeXMK { latexmk, dir, docfile, tmpdir, outdir: outdir.into(), opts: latexmk_opts }
+        LaTeXMK { latexmk, dir, docfile, tmpdir, outdir: outdir.into(), opts: latexmk_opts, pretex: self.pretex() }
     }
     pub fn latexmk_for_diff<'a>(&'a self) -> LaTeXMK<'a> {
         let Param { latexmk, tmpdir, diff_docfile, latexmk_opts, .. } = self;
-        let outdir = self.dir.join(&self.diff_dir_name).into();
-        LaTeXMK { latexmk, dir: tmpdir, docfile: diff_docfile, tmpdir, outdir, opts: latexmk_opts }
+        LaTeXMK { latexmk, dir: tmpdir, docfile: diff_docfile, tmpdir, outdir: self.diff_outdir().into(), opts: latexmk_opts, pretex: None }
+    }
+    /// 別の場所にある同じ文書を、同じ設定でタイプセットする(古いリビジョンのビルドなど)
+    pub fn latexmk_in<'a>(&'a self, dir: &'a Path, tmpdir: &'a Path) -> LaTeXMK<'a> {
+        let Param { latexmk, docfile, latexmk_opts, .. } = self;
+        LaTeXMK { latexmk, dir, docfile, tmpdir, outdir: tmpdir.into(), opts: latexmk_opts, pretex: self.pretex() }
+    }
+    /// 新旧の文書のビルドに読ませるコード。`--side-by-side`では見出しが出力された物理的なページを`.aux`に書かせる
+    pub fn pretex(&self) -> Option<&'static str> {
+        self.side_by_side.then_some(sidebyside::TOC_PAGE_HOOK)
     }
     pub fn latexdiff_vc<'a>(&'a self) -> LatexdiffVc<'a> {
         let Param { latexdiff_vc, dir, docfile, diff_dir_name, tmpdir, latexdiff_opts, latexdiffvc_opts: opts, diff_docfile, .. } = self;
         let verbose = self.latexmk_opts.verbose;
         LatexdiffVc { latexdiff_vc, dir, docfile, diff_dir_name, verbose, opts, latexdiff_opts, tmpdir, diff_docfile }
     }
+    /// latexdiff-vcを通さずに二つの`.tex`を比べる(取り出したツリー同士など)
+    pub fn latexdiff<'a>(&'a self, old: &'a Path, new: &'a Path) -> Latexdiff<'a> {
+        let vc = &self.latexdiffvc_opts;
+        // `--old-archive`や二つ目のリビジョンの取り込むファイルは、差分を作業ツリーでタイプセットすると手元のものに置き換わる。
+        // 書き直した取り込むファイルも、展開しなければlatexdiffに読まれない
+        let flatten = vc.flatten || vc.flatten_keep_intermediate || self.old_source.is_some() || self.new_revision().is_some() || self.rewrites_sources();
+        Latexdiff { latexdiff: &self.latexdiff, old, new, flatten, verbose: self.latexmk_opts.verbose, opts: &self.latexdiff_opts }
+    }
+    /// latexdiffにかける前に、取り込むファイルまで含めて両側のソースを書き直すか
+    pub fn rewrites_sources(&self) -> bool {
+        self.scope.is_some() || self.mask.is_some() || self.ignore_noise || self.expand_macros
+    }
+    /// `-r A -r B`で作業ツリーの代わりに比べるリビジョン`B`
+    pub fn new_revision(&self) -> Option<&str> {
+        self.latexdiffvc_opts.revision.get(1).map(String::as_str)
+    }
+    /// 比較元のリビジョン。指定が無ければlatexdiff-vcと同じくHEAD(に当たるもの)
+    pub fn old_revision(&self) -> &str {
+        if let Some(old) = &self.old_source {
+            return old.path().to_str().unwrap_or("the old version");
+        }
+        if let Some(Against::Index) = self.against {
+            return "the index";
+        }
+        let vc = &self.latexdiffvc_opts;
+        // 作業コピーの親。jjでは`@`が作業コピーそのもの
+        let default = if vc.jj { "@-" } else if vc.hg { "." } else { "HEAD" };
+        vc.revision.first().map_or(default, String::as_str)
+    }
+    /// latexdiff-vcを通さず、古い側を取り出して直接latexdiffにかけるか
+    pub fn diffs_directly(&self) -> bool {
+        // latexdiff-vcはjjを知らない。hgも取り出したものを比べれば、gitと同じく`-r A -r B`や古い版のビルドが使える
+        // 範囲を絞るとき、印を付けない部分を揃えるとき、マクロを展開するとき、正規化するときはソースを作り替える
+        matches!(self.against, Some(Against::Index)) || self.old_source.is_some() || self.latexdiffvc_opts.jj || self.latexdiffvc_opts.hg || self.scope.is_some() || self.mask.is_some() || self.ignore_noise || self.expand_macros
+    }
+    /// 差分`.tex`に`\DIFhunk`を差し込む必要があるか(変更の位置を使う出力があるか)
+    pub fn marks_hunks(&self) -> bool {
+        self.latexdiffvc_opts.only_changes || self.change_index || self.summary || self.response || self.blame_notes.is_some() || self.color_by_author
+    }
+    /// `--baseline`の一つについて差分を作るための設定。出力先と一時ディレクトリを分け、他の差分と並行して作れるようにする
+    pub fn for_baseline(&self, baseline: &NamedBaseline, revision: String) -> Param {
+        let mut param = self.clone();
+        param.diff_dir_name = format!("{}-{}", self.diff_dir_name, baseline.name);
+        param.diff_docfile = osstr_join(&self.diff_docfile, &format!("-{}", baseline.name));
+        param.tmpdir = self.tmpdir.join(format!("baseline-{}", baseline.name));
+        param.latexdiffvc_opts.revision = vec![revision];
+        // 出力を横取りされないよう捕まえておき、メインのビルドに依存するレポートは作らない
+        param.diff_only = false;
+        param.async_diff = false;
+        param.baselines.clear();
+        param.against = None;
+        param.old_source = None;
+        param
+    }
+    /// 差分PDFを置くディレクトリ
+    pub fn diff_outdir(&self) -> PathBuf {
+        self.dir.join(&self.diff_dir_name)
+    }
+    /// latexdiff-vcの出力を移した先(`tmpdir`内の差分`.tex`)
+    pub fn diff_tex_path(&self) -> PathBuf {
+        self.tmpdir.join(osstr_join(&self.diff_docfile, ".tex"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_are_consistent() {
+        <Opts as clap::CommandFactory>::command().debug_assert();
+    }
+
+    #[test]
+    fn reads_series_ranges() {
+        assert_eq!(parse_range("v1..HEAD").unwrap(), "v1..HEAD");
+        assert_eq!(parse_range("HEAD~5").unwrap(), "HEAD~5..HEAD");
+        assert_eq!(parse_range("v1..").unwrap(), "v1..HEAD");
+        assert_eq!(parse_range("..topic").unwrap(), "HEAD..topic");
+        assert!(parse_range("v1...v2").is_err());
+        assert!(parse_range("..").is_err());
+        assert!(parse_range("").is_err());
+    }
 }
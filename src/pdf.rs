@@ -0,0 +1,185 @@
+//! 既存のPDFのページを並べ直して新しいPDFを作る。
+//! 各ページをフォームXObjectとして取り込み、新しいページの上に好きな位置で描く。
+
+use std::path::{Path, PathBuf};
+
+use lopdf::{Dictionary, Document, Object, ObjectId, Stream, dictionary};
+
+use crate::error::{self, Error};
+
+/// 取り込んだページ1枚分
+#[derive(Debug, Clone, Copy)]
+pub struct Form {
+    id: ObjectId,
+    pub width: f32,
+    pub height: f32,
+}
+
+pub struct Builder {
+    doc: Document,
+    pages_id: ObjectId,
+    pages: Vec<Object>,
+}
+impl Builder {
+    pub fn new() -> Builder {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        Builder { doc, pages_id, pages: Vec::new() }
+    }
+
+    /// `path`のPDFの全ページを取り込む
+    pub fn import(&mut self, path: &Path) -> error::Result<Vec<Form>> {
+        let mut source = Document::load(path).map_err(|e| pdf_failed(path, e))?;
+        // オブジェクト番号が被らないようにずらしてから丸ごと移す。使われないものは保存時に捨てる
+        source.renumber_objects_with(self.doc.max_id + 1);
+        let mut forms = Vec::new();
+        for page_id in source.get_pages().into_values() {
+            let [x0, y0, x1, y1] = media_box(&source, page_id);
+            let resources = inherited(&source, page_id, b"Resources").unwrap_or_else(|| Dictionary::new().into());
+            let content = source.get_page_content(page_id);
+            let form = Stream::new(
+                dictionary! {
+                    "Type" => "XObject",
+                    "Subtype" => "Form",
+                    "BBox" => vec![x0.into(), y0.into(), x1.into(), y1.into()],
+                    // 原点が(0, 0)でないページもあるので、左下が原点に来るようにずらす
+                    "Matrix" => vec![1.into(), 0.into(), 0.into(), 1.into(), (-x0).into(), (-y0).into()],
+                    "Resources" => resources,
+                },
+                content,
+            );
+            let id = source.add_object(form);
+            forms.push(Form { id, width: x1 - x0, height: y1 - y0 });
+        }
+        self.doc.max_id = source.max_id;
+        self.doc.objects.append(&mut source.objects);
+        Ok(forms)
+    }
+
+    /// `width`x`height`のページを作り、`(form, x, y)`の位置にそれぞれ描く
+    pub fn add_page(&mut self, width: f32, height: f32, placements: &[(Form, f32, f32)]) {
+        let mut xobjects = Dictionary::new();
+        let mut content = String::new();
+        for (n, (form, x, y)) in placements.iter().enumerate() {
+            let name = format!("P{n}");
+            content.push_str(&format!("q 1 0 0 1 {x} {y} cm /{name} Do Q\n"));
+            xobjects.set(name, form.id);
+        }
+        self.push_page(width, height, dictionary! { "XObject" => xobjects }, content.into_bytes());
+    }
+
+    /// 文字だけのページを作る。入りきらない行は次のページに送る
+    pub fn add_text_pages(&mut self, width: f32, height: f32, lines: &[String]) {
+        const SIZE: f32 = 11.0;
+        const LEADING: f32 = 15.0;
+        let margin = 56.0;
+        let per_page = (((height - 2.0 * margin) / LEADING) as usize).max(1);
+        for chunk in lines.chunks(per_page) {
+            let mut content = format!("BT /F1 {SIZE} Tf {LEADING} TL {margin} {} Td\n", height - margin - SIZE);
+            for line in chunk {
+                content.push_str(&format!("({}) Tj T*\n", escape(line)));
+            }
+            content.push_str("ET\n");
+            let font = dictionary! { "Type" => "Font", "Subtype" => "Type1", "BaseFont" => "Helvetica" };
+            self.push_page(width, height, dictionary! { "Font" => dictionary! { "F1" => font } }, content.into_bytes());
+        }
+    }
+
+    fn push_page(&mut self, width: f32, height: f32, resources: Dictionary, content: Vec<u8>) {
+        let content_id = self.doc.add_object(Stream::new(Dictionary::new(), content));
+        let page_id = self.doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => self.pages_id,
+            "MediaBox" => vec![0.into(), 0.into(), width.into(), height.into()],
+            "Resources" => resources,
+            "Contents" => content_id,
+        });
+        self.pages.push(page_id.into());
+    }
+
+    pub fn save(mut self, path: &Path) -> error::Result<()> {
+        let count = self.pages.len() as i64;
+        let pages = dictionary! { "Type" => "Pages", "Kids" => self.pages, "Count" => count };
+        self.doc.objects.insert(self.pages_id, pages.into());
+        let catalog_id = self.doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => self.pages_id });
+        self.doc.trailer.set("Root", catalog_id);
+        self.doc.prune_objects();
+        self.doc.compress();
+        self.doc.save(path).map_err(|e| Error::FileWriteFailed { path: path.to_owned(), source: e })?;
+        Ok(())
+    }
+}
+
+/// PDFの文字列リテラル用のエスケープ。標準フォントで出せない文字は`?`にする
+fn escape(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '(' | ')' | '\\' => format!("\\{c}"),
+            c if c.is_ascii() && !c.is_ascii_control() => c.to_string(),
+            _ => "?".to_string(),
+        })
+        .collect()
+}
+
+fn pdf_failed(path: &Path, source: lopdf::Error) -> Error {
+    Error::PdfFailed { path: PathBuf::from(path), source }
+}
+
+/// `MediaBox`などはページツリーの親から継承されることがある
+fn inherited(doc: &Document, mut node: ObjectId, key: &[u8]) -> Option<Object> {
+    for _ in 0..32 {
+        let dict = doc.get_dictionary(node).ok()?;
+        if let Ok(value) = dict.get(key) {
+            return Some(value.clone());
+        }
+        node = dict.get(b"Parent").and_then(Object::as_reference).ok()?;
+    }
+    None
+}
+
+fn media_box(doc: &Document, page_id: ObjectId) -> [f32; 4] {
+    let a4 = [0.0, 0.0, 595.0, 842.0];
+    let Some(value) = inherited(doc, page_id, b"MediaBox") else { return a4 };
+    let value = match value {
+        Object::Reference(id) => doc.get_object(id).cloned().unwrap_or(value),
+        v => v,
+    };
+    let Ok(array) = value.as_array() else { return a4 };
+    let nums: Vec<f32> = array.iter().filter_map(|n| n.as_float().ok()).collect();
+    match nums[..] {
+        [x0, y0, x1, y1] => [x0, y0, x1, y1],
+        _ => a4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    /// 幅でページを見分けられるPDF
+    fn widths(path: &Path) -> Vec<f32> {
+        let doc = Document::load(path).unwrap();
+        doc.get_pages().into_values().map(|id| media_box(&doc, id)[2]).collect()
+    }
+
+    #[test]
+    fn selects_and_places_pages() {
+        let dir = TempDir::new("pdf");
+        let mut source = Builder::new();
+        for width in [100.0, 200.0, 300.0] {
+            source.add_text_pages(width, 400.0, &["x".to_string()]);
+        }
+        source.save(&dir.join("source.pdf")).unwrap();
+        assert_eq!(widths(&dir.join("source.pdf")), [100.0, 200.0, 300.0]);
+
+        let mut builder = Builder::new();
+        let forms = builder.import(&dir.join("source.pdf")).unwrap();
+        assert_eq!(forms.iter().map(|f| f.width).collect::<Vec<_>>(), [100.0, 200.0, 300.0]);
+        builder.add_text_pages(50.0, 400.0, &["index".to_string()]);
+        builder.add_page(forms[2].width, forms[2].height, &[(forms[2], 0.0, 0.0)]);
+        builder.add_page(forms[0].width + forms[1].width, 400.0, &[(forms[0], 0.0, 0.0), (forms[1], forms[0].width, 0.0)]);
+        builder.save(&dir.join("out.pdf")).unwrap();
+        assert_eq!(widths(&dir.join("out.pdf")), [50.0, 300.0, 300.0]);
+    }
+}
@@ -0,0 +1,144 @@
+//! プリアンブルの変更(クラス、パッケージとそのオプション、マクロの定義)をまとめ、差分PDFの最初のページにする。
+//! latexdiffはプリアンブルに印を付けないが、定義の変更は文書全体の見た目を変える。
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+};
+
+use crate::{
+    baseline::Baseline,
+    difftex::DiffTex,
+    osstr_join,
+    param::Param,
+    tex,
+};
+
+const PACKAGE_CMDS: &[&str] = &["usepackage", "RequirePackage"];
+const NEWENV_CMDS: &[&str] = &["newenvironment", "renewenvironment"];
+
+/// プリアンブルから読み取ったもの
+#[derive(Debug, Default)]
+struct Preamble {
+    /// クラス名とオプション
+    class: Option<(String, String)>,
+    /// パッケージ名とオプション
+    packages: BTreeMap<String, String>,
+    /// マクロ(環境なら名前そのもの)と定義全体。後の定義で上書きする
+    macros: BTreeMap<String, String>,
+}
+
+fn parse(src: &str) -> Preamble {
+    let end = tex::commands(src, &["begin"], 1).into_iter().find(|cmd| cmd.args[0].trim() == "document").map_or(src.len(), |cmd| cmd.start);
+    let src = &src[..end];
+    let mut preamble = Preamble::default();
+    let options = |opt: Option<&str>| tex::split_list(opt.unwrap_or_default()).collect::<Vec<_>>().join(",");
+    if let Some(cmd) = tex::commands(src, &["documentclass"], 1).into_iter().next() {
+        preamble.class = Some((cmd.args[0].trim().to_string(), options(cmd.opt)));
+    }
+    for cmd in tex::commands(src, PACKAGE_CMDS, 1) {
+        for name in tex::split_list(cmd.args[0]) {
+            preamble.packages.insert(name.to_string(), options(cmd.opt));
+        }
+    }
+    let bytes = src.as_bytes();
+    for def in tex::definitions(src) {
+        preamble.macros.insert(format!("\\{}", def.name), tex::collapse_ws(&src[def.range]));
+    }
+    for cmd in tex::commands(src, NEWENV_CMDS, 1) {
+        // 開始と終了の二つの本体の終わりまでを定義とする。間にある引数の数と省略時の値は読み飛ばす
+        let mut at = cmd.end;
+        let mut bodies = 0;
+        while bodies < 2 {
+            let i = at + src[at..].len() - src[at..].trim_start().len();
+            let close = match bytes.get(i) {
+                Some(b'[') => tex::group_end(bytes, i, b'[', b']'),
+                Some(b'{') => {
+                    bodies += 1;
+                    tex::group_end(bytes, i, b'{', b'}')
+                }
+                _ => None,
+            };
+            let Some(close) = close else { break };
+            at = close + 1;
+        }
+        preamble.macros.insert(cmd.args[0].trim().to_string(), tex::collapse_ws(&src[cmd.start..at]));
+    }
+    preamble
+}
+
+/// 変更点を一行ずつ(LaTeXに書く前の文)
+fn changes(old: &Preamble, new: &Preamble) -> Vec<String> {
+    let mut lines = Vec::new();
+    let with_opts = |name: &str, opts: &str| if opts.is_empty() { name.to_string() } else { format!("{name} [{opts}]") };
+    if let (Some((old_name, old_opts)), Some((new_name, new_opts))) = (&old.class, &new.class)
+        && (old_name, old_opts) != (new_name, new_opts)
+    {
+        lines.push(format!("Document class: {} -> {}", with_opts(old_name, old_opts), with_opts(new_name, new_opts)));
+    }
+    let names: BTreeSet<&String> = old.packages.keys().chain(new.packages.keys()).collect();
+    for name in names {
+        match (old.packages.get(name), new.packages.get(name)) {
+            (None, Some(opts)) => lines.push(format!("Package added: {}", with_opts(name, opts))),
+            (Some(_), None) => lines.push(format!("Package removed: {name}")),
+            (Some(o), Some(n)) if o != n => lines.push(format!("Package options changed: {name} [{o}] -> [{n}]")),
+            _ => {}
+        }
+    }
+    let names: BTreeSet<&String> = old.macros.keys().chain(new.macros.keys()).collect();
+    for name in names {
+        match (old.macros.get(name), new.macros.get(name)) {
+            (None, Some(def)) => lines.push(format!("Definition added: {def}")),
+            (Some(_), None) => lines.push(format!("Definition removed: {name}")),
+            (Some(o), Some(n)) if o != n => lines.push(format!("Definition changed: {o} -> {n}")),
+            _ => {}
+        }
+    }
+    lines
+}
+
+/// プリアンブルに変更があれば、その一覧のページを差分の本文の最初に差し込む
+pub fn add_page(param: &Param, baseline: &Baseline, diff_tex: &mut DiffTex) {
+    let new_src = tex::flatten(&PathBuf::from(osstr_join(&param.docfile, ".tex")), &mut tex::read_from_dir(&param.dir)).text();
+    let lines = changes(&parse(&baseline.flatten().text()), &parse(&new_src));
+    if lines.is_empty() {
+        return;
+    }
+    let mut page = String::from("\\thispagestyle{empty}\n");
+    page.push_str(&format!("{{\\noindent\\Large\\bfseries Preamble changes since {}\\par}}\n", tex::escape(param.old_revision())));
+    page.push_str("\\begin{itemize}\\raggedright\n");
+    for line in &lines {
+        page.push_str(&format!("\\item {}\n", tex::escape(line).replace("->", "$\\to$")));
+    }
+    // このページを数えず、本文のページ番号を新しい版のPDFと揃える
+    page.push_str("\\end{itemize}\n\\clearpage\n\\setcounter{page}{1}\n");
+    diff_tex.add_front_page(&page);
+    println!("Preamble: {} changes", lines.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_preamble_changes() {
+        let old = parse(
+            "\\documentclass[a4paper]{article}\n\\usepackage{amsmath,graphicx}\n\\newcommand{\\R}{\\mathbb{R}}\n\\newenvironment{note}{\\itshape}{}\n\\begin{document}\n\\usepackage{ignored}\n",
+        );
+        let new = parse(
+            "\\documentclass[a4paper, 11pt]{article}\n\\usepackage[final]{graphicx}\n\\usepackage{hyperref}\n\\newcommand{\\R}{\\mathbf{R}}\n\\newenvironment{note}[1][x]{\\itshape}{}\n\\begin{document}\n",
+        );
+        assert_eq!(
+            changes(&old, &new),
+            [
+                "Document class: article [a4paper] -> article [a4paper,11pt]",
+                "Package removed: amsmath",
+                "Package options changed: graphicx [] -> [final]",
+                "Package added: hyperref",
+                "Definition changed: \\newcommand{\\R}{\\mathbb{R}} -> \\newcommand{\\R}{\\mathbf{R}}",
+                "Definition changed: \\newenvironment{note}{\\itshape}{} -> \\newenvironment{note}[1][x]{\\itshape}{}",
+            ]
+        );
+        assert!(changes(&old, &old).is_empty());
+    }
+}
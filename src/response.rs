@@ -0,0 +1,292 @@
+//! 査読者への回答書の雛形(`<doc>-response.tex`と`.md`)を作る。
+//! 変更の索引(changes::index)を使い、変更ごとに新しい文の引用と場所、回答を書く欄を並べる。
+//! 査読コメントのYAMLがあれば、ラベルや検索文字列で変更と結び付けてコメントごとにまとめる。
+
+use std::{collections::BTreeSet, path::Path};
+
+use crate::{
+    changes::{self, Change},
+    difftex::{DiffTex, HunkKind},
+    error::{self, Error},
+    osstr_join,
+    param::Param,
+    tex,
+};
+
+/// YAMLに書く査読コメント一つ分
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct Comment {
+    #[serde(deserialize_with = "scalar")]
+    pub reviewer: Option<String>,
+    #[serde(deserialize_with = "scalar")]
+    pub id: Option<String>,
+    pub comment: String,
+    /// このラベルを含む(またはラベルのある節の)変更と結び付ける
+    pub labels: Vec<String>,
+    /// 追加・削除された文にこの文字列を含む変更と結び付ける(大文字小文字は区別しない)
+    pub search: Vec<String>,
+}
+
+/// `reviewer: 1`のように数で書かれても文字列として読む
+fn scalar<'de, D: serde::Deserializer<'de>>(de: D) -> Result<Option<String>, D::Error> {
+    let value: Option<serde_yaml::Value> = serde::Deserialize::deserialize(de)?;
+    Ok(value.map(|value| match value {
+        serde_yaml::Value::String(s) => s,
+        other => serde_yaml::to_string(&other).unwrap_or_default().trim().to_string(),
+    }))
+}
+
+fn read_comments(path: &Path) -> error::Result<Vec<Comment>> {
+    let yaml = error::read(path)?;
+    serde_yaml::from_slice(&yaml).map_err(|e| Error::ParseFailed { path: path.to_owned(), message: e.to_string() })
+}
+
+/// コメントごとに、結び付いた変更の番号(0始まり)
+fn link(comments: &[Comment], changes: &[Change], diff_tex: &DiffTex) -> Vec<BTreeSet<usize>> {
+    let hunks = diff_tex.hunks();
+    let headings = diff_tex.headings();
+    let label_at = |key: &str| {
+        let labels = tex::commands(&diff_tex.src, &["label"], 1);
+        labels.into_iter().find(|cmd| cmd.args[0].trim() == key).map(|cmd| cmd.start)
+    };
+    comments
+        .iter()
+        .map(|comment| {
+            let mut linked = BTreeSet::new();
+            for key in &comment.labels {
+                // 変更の中でラベルを定義・参照していればそれ、無ければラベルのある節(小節)の変更全部
+                let needle = format!("{{{key}}}");
+                let inside: Vec<usize> = (0..hunks.len()).filter(|&n| diff_tex.src[hunks[n].range.clone()].contains(&needle)).collect();
+                if !inside.is_empty() {
+                    linked.extend(inside);
+                    continue;
+                }
+                let Some(at) = label_at(key) else { continue };
+                let (section, subsection) = changes::enclosing(&headings, at);
+                linked.extend((0..changes.len()).filter(|&n| {
+                    changes[n].section == section && (subsection.is_none() || changes[n].subsection == subsection)
+                }));
+            }
+            for needle in comment.search.iter().map(|s| s.to_lowercase()) {
+                linked.extend((0..changes.len()).filter(|&n| {
+                    let change = &changes[n];
+                    change.added.to_lowercase().contains(&needle) || change.deleted.to_lowercase().contains(&needle)
+                }));
+            }
+            linked
+        })
+        .collect()
+}
+
+/// 改訂版での場所。SyncTeXが無ければ差分PDFのページで代用する
+fn location(change: &Change) -> String {
+    let page = match (change.main_page, &change.diff_page) {
+        (Some(page), _) => format!("p. {page}"),
+        (None, Some(page)) => format!("p. {page} of the diff"),
+        (None, None) => String::new(),
+    };
+    let lines = match (&change.file, change.lines) {
+        (Some(file), Some([lo, hi])) if lo == hi => format!("{} l. {lo}", file.display()),
+        (Some(file), Some([lo, hi])) => format!("{} ll. {lo}-{hi}", file.display()),
+        _ => String::new(),
+    };
+    [page, lines].into_iter().filter(|s| !s.is_empty()).collect::<Vec<_>>().join(", ")
+}
+
+/// 引用する文。変更のあった行全体を新しいソースから取り、削除だけの変更は削除された文にする
+fn quote(dir: &Path, change: &Change) -> (&'static str, String) {
+    if change.kind == HunkKind::Delete {
+        return ("Deleted", change.deleted.clone());
+    }
+    let whole_lines = match (&change.file, change.lines) {
+        (Some(file), Some([lo, hi])) => std::fs::read_to_string(dir.join(file))
+            .ok()
+            .map(|src| tex::plain_text(&src.lines().skip(lo - 1).take(hi + 1 - lo).collect::<Vec<_>>().join("\n"))),
+        _ => None,
+    };
+    ("Revised text", whole_lines.filter(|text| !text.is_empty()).unwrap_or_else(|| change.added.clone()))
+}
+
+fn comment_title(n: usize, comment: &Comment) -> String {
+    let id = comment.id.clone().unwrap_or_else(|| (n + 1).to_string());
+    match &comment.reviewer {
+        Some(reviewer) => format!("Reviewer {reviewer}, comment {id}"),
+        None => format!("Comment {id}"),
+    }
+}
+
+fn latex(comments: &[Comment], links: &[BTreeSet<usize>], changes: &[Change], quotes: &[(&str, String)]) -> String {
+    let mut out = String::from("\\documentclass{article}\n\\begin{document}\n\\section*{Response to the reviewers}\n\n");
+    let change_tex = |out: &mut String, change: &Change| {
+        out.push_str(&format!("\\paragraph{{Change {} ({})}}\n", change.id, tex::escape(&location(change))));
+        let (what, text) = &quotes[change.id - 1];
+        if !text.is_empty() {
+            out.push_str(&format!("{what}:\n\\begin{{quote}}\n{}\n\\end{{quote}}\n", tex::escape(text)));
+        }
+    };
+    for (n, comment) in comments.iter().enumerate() {
+        out.push_str(&format!("\\subsection*{{{}}}\n", tex::escape(&comment_title(n, comment))));
+        out.push_str(&format!("\\begin{{quote}}\\itshape\n{}\n\\end{{quote}}\n", tex::escape(comment.comment.trim())));
+        out.push_str("\\textbf{Reply:} % TODO\n\n");
+        for &c in &links[n] {
+            change_tex(&mut out, &changes[c]);
+        }
+        out.push('\n');
+    }
+    // どのコメントにも結び付かなかった変更は節ごとに並べる
+    let linked: BTreeSet<usize> = links.iter().flatten().copied().collect();
+    let mut section = None;
+    for change in changes.iter().enumerate().filter(|(n, _)| !linked.contains(n)).map(|(_, c)| c) {
+        if section != Some(&change.section) {
+            let title = change.section.as_deref().unwrap_or("Other changes");
+            out.push_str(&format!("\\subsection*{{{}}}\n", tex::escape(title)));
+            section = Some(&change.section);
+        }
+        change_tex(&mut out, change);
+        out.push_str("\\textbf{Reply:} % TODO\n\n");
+    }
+    out.push_str("\\end{document}\n");
+    out
+}
+
+fn markdown(comments: &[Comment], links: &[BTreeSet<usize>], changes: &[Change], quotes: &[(&str, String)]) -> String {
+    let mut out = String::from("# Response to the reviewers\n\n");
+    let change_md = |out: &mut String, change: &Change| {
+        out.push_str(&format!("**Change {}** ({})\n\n", change.id, location(change)));
+        let (what, text) = &quotes[change.id - 1];
+        if !text.is_empty() {
+            out.push_str(&format!("{what}:\n\n> {text}\n\n"));
+        }
+    };
+    for (n, comment) in comments.iter().enumerate() {
+        out.push_str(&format!("## {}\n\n", comment_title(n, comment)));
+        let quoted: Vec<String> = comment.comment.trim().lines().map(|l| format!("> {l}")).collect();
+        out.push_str(&format!("{}\n\n**Reply:** TODO\n\n", quoted.join("\n")));
+        for &c in &links[n] {
+            change_md(&mut out, &changes[c]);
+        }
+    }
+    let linked: BTreeSet<usize> = links.iter().flatten().copied().collect();
+    let mut section = None;
+    for change in changes.iter().enumerate().filter(|(n, _)| !linked.contains(n)).map(|(_, c)| c) {
+        if section != Some(&change.section) {
+            out.push_str(&format!("## {}\n\n", change.section.as_deref().unwrap_or("Other changes")));
+            section = Some(&change.section);
+        }
+        change_md(&mut out, change);
+        out.push_str("**Reply:** TODO\n\n");
+    }
+    out
+}
+
+/// `<doc>-response.tex`と`<doc>-response.md`を差分PDFの隣に書く
+pub fn write(param: &Param) -> error::Result<()> {
+    let changes = changes::index(param)?;
+    let comments = match &param.reviewer_comments {
+        Some(path) => read_comments(path)?,
+        None => Vec::new(),
+    };
+    let links = link(&comments, &changes, &DiffTex::read(param.diff_tex_path())?);
+    let quotes: Vec<_> = changes.iter().map(|change| quote(&param.dir, change)).collect();
+    error::create_dir_all(param.diff_outdir())?;
+    let tex = latex(&comments, &links, &changes, &quotes);
+    error::write(param.diff_outdir().join(osstr_join(&param.docfile, "-response.tex")), tex)?;
+    let md = markdown(&comments, &links, &changes, &quotes);
+    error::write(param.diff_outdir().join(osstr_join(&param.docfile, "-response.md")), md)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    const DIFF: &str = "\\documentclass{article}\n\\begin{document}\n\\section{Intro}\n\
+        \\DIFhunk{1}\\DIFaddbegin \\DIFadd{See Fig.~\\ref{fig:plot}.}\\DIFaddend\n\
+        \\section{Method}\\label{sec:method}\n\
+        Text \\DIFhunk{2}\\DIFdelbegin \\DIFdel{slow solver}\\DIFdelend \\DIFhunk{3}\\DIFaddbegin \\DIFadd{Faster Solver}\\DIFaddend .\n\
+        \\section{Results}\n\\DIFhunk{4}\\DIFdelbegin \\DIFdel{Removed claim.}\\DIFdelend\n\\end{document}\n";
+
+    fn change(id: usize, kind: HunkKind, section: &str, deleted: &str, added: &str) -> Change {
+        Change {
+            id,
+            kind,
+            file: Some("main.tex".into()),
+            lines: Some([id + 2, id + 2]),
+            section: Some(section.to_string()),
+            subsection: None,
+            diff_page: Some("1".to_string()),
+            diff_abs_page: Some(1),
+            main_page: None,
+            deleted: deleted.to_string(),
+            added: added.to_string(),
+        }
+    }
+
+    fn changes() -> Vec<Change> {
+        vec![
+            change(1, HunkKind::Add, "Intro", "", "See Fig. 1."),
+            change(2, HunkKind::Replace, "Method", "slow solver", "Faster Solver"),
+            change(3, HunkKind::Delete, "Results", "Removed claim.", ""),
+        ]
+    }
+
+    fn comment(labels: &[&str], search: &[&str]) -> Comment {
+        Comment {
+            comment: "Please clarify.".to_string(),
+            labels: labels.iter().map(|s| s.to_string()).collect(),
+            search: search.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn links_comments_by_label_and_search() {
+        let dir = TempDir::new("response");
+        error::write(dir.join("diff.tex"), DIFF).unwrap();
+        let diff_tex = DiffTex::read(dir.join("diff.tex")).unwrap();
+        let comments = [
+            // 変更の中で参照しているラベル
+            comment(&["fig:plot"], &[]),
+            // 変更の外のラベルは、その節の変更全部
+            comment(&["sec:method"], &[]),
+            comment(&[], &["faster SOLVER", "removed"]),
+            comment(&["sec:nowhere"], &["not in the text"]),
+        ];
+        let links = link(&comments, &changes(), &diff_tex);
+        assert_eq!(links, [BTreeSet::from([0]), BTreeSet::from([1]), BTreeSet::from([1, 2]), BTreeSet::new()]);
+    }
+
+    #[test]
+    fn quotes_whole_revised_lines() {
+        let dir = TempDir::new("response-quote");
+        error::write(dir.join("main.tex"), "line one\nline two\nline three\n").unwrap();
+        let mut added = change(1, HunkKind::Add, "Intro", "", "two");
+        added.lines = Some([2, 3]);
+        assert_eq!(quote(&dir, &added), ("Revised text", "line two line three".to_string()));
+        let [_, mut missing, deleted] = changes().try_into().unwrap();
+        missing.file = Some("gone.tex".into());
+        assert_eq!(quote(&dir, &missing), ("Revised text", "Faster Solver".to_string()));
+        assert_eq!(quote(&dir, &deleted), ("Deleted", "Removed claim.".to_string()));
+    }
+
+    #[test]
+    fn writes_linked_changes_under_comments_and_the_rest_by_section() {
+        let changes = changes();
+        let comments = [Comment { reviewer: Some("2".to_string()), id: Some("a".to_string()), comment: "Explain 50% speed-up".to_string(), ..Default::default() }];
+        let links = [BTreeSet::from([1])];
+        let quotes: Vec<_> = changes.iter().map(|c| if c.kind == HunkKind::Delete { ("Deleted", c.deleted.clone()) } else { ("Revised text", c.added.clone()) }).collect();
+
+        let tex = latex(&comments, &links, &changes, &quotes);
+        let order = ["\\subsection*{Reviewer 2, comment a}", "Explain 50\\% speed-up", "\\paragraph{Change 2 (p. 1 of the diff, main.tex l. 4)}", "Faster Solver", "\\subsection*{Intro}", "Change 1", "\\subsection*{Results}", "Deleted:", "Removed claim."];
+        let at: Vec<usize> = order.iter().map(|s| tex.find(s).unwrap_or_else(|| panic!("{s} not in {tex}"))).collect();
+        assert!(at.is_sorted(), "{tex}");
+        assert!(!tex.contains("\\subsection*{Method}"));
+
+        let md = markdown(&comments, &links, &changes, &quotes);
+        let order = ["## Reviewer 2, comment a", "> Explain 50% speed-up", "**Change 2** (p. 1 of the diff, main.tex l. 4)", "> Faster Solver", "## Intro", "**Change 1**", "## Results", "> Removed claim."];
+        let at: Vec<usize> = order.iter().map(|s| md.find(s).unwrap_or_else(|| panic!("{s} not in {md}"))).collect();
+        assert!(at.is_sorted(), "{md}");
+        assert!(!md.contains("## Method"));
+    }
+}
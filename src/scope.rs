@@ -0,0 +1,86 @@
+//! `--only-paths`と`--exclude-paths`で、差分を付けるファイルを絞る。
+//! 範囲外のファイルは古い側を新しい側のもので置き換えてからlatexdiffにかけるので、差分が付かずに今の文がそのまま出る。
+//! 文書全体はどちらも揃っているので、番号などは絞らない場合と変わらない。
+
+use std::path::{Path, PathBuf};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+use crate::{
+    baseline::Baseline,
+    error::{self, Error},
+    osstr_join,
+    param::Param,
+    tex,
+};
+
+/// 差分を付けるファイルの範囲。パターンは文書ディレクトリからの相対パスに対するもの
+#[derive(Debug, Clone)]
+pub struct Scope {
+    only: Option<GlobSet>,
+    exclude: GlobSet,
+}
+impl Scope {
+    /// どちらも指定されていなければ`None`
+    pub fn new(only: Vec<Glob>, exclude: Vec<Glob>) -> error::Result<Option<Scope>> {
+        if only.is_empty() && exclude.is_empty() {
+            return Ok(None);
+        }
+        let build = |globs: Vec<Glob>| {
+            let mut set = GlobSetBuilder::new();
+            for glob in globs {
+                set.add(glob);
+            }
+            set.build().map_err(|e| Error::ParseFailed { path: PathBuf::from(e.glob().unwrap_or_default()), message: e.to_string() })
+        };
+        let only = if only.is_empty() { None } else { Some(build(only)?) };
+        Ok(Some(Scope { only, exclude: build(exclude)? }))
+    }
+    pub fn contains(&self, path: &Path) -> bool {
+        self.only.as_ref().is_none_or(|only| only.is_match(path)) && !self.exclude.is_match(path)
+    }
+}
+
+/// 範囲外の`.tex`を新しい側(メインの`.tex`が`new`)のものに差し替えた古い側を`dir`に作り、そのメインの`.tex`を返す。
+/// latexdiffが読むのは`.tex`だけなので、画像などは置かない
+pub fn old_tex(param: &Param, scope: &Scope, baseline: &Baseline, new: &Path, dir: &Path) -> error::Result<PathBuf> {
+    error::remove_dir_all(dir)?;
+    let main = PathBuf::from(osstr_join(&param.docfile, ".tex"));
+    let old_files = baseline.flatten().files;
+    let new_dir = new.parent().unwrap_or(&param.dir);
+    let new_files = tex::flatten(&main, &mut tex::read_from_dir(new_dir)).files;
+    for file in old_files.iter().chain(&new_files) {
+        // 範囲外のファイルは新しい側のもの。どちらかにしか無ければ、その有無も新しい側に合わせる
+        let content = match scope.contains(file) {
+            true => baseline.read(file),
+            false => std::fs::read(new_dir.join(file)).ok(),
+        };
+        let Some(content) = content else { continue };
+        let dest = dir.join(file);
+        error::create_dir_all(dest.parent().unwrap_or(dir))?;
+        error::write(dest, content)?;
+    }
+    Ok(dir.join(main))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn globs(patterns: &[&str]) -> Vec<Glob> {
+        patterns.iter().map(|p| Glob::new(p).unwrap()).collect()
+    }
+
+    #[test]
+    fn only_and_exclude() {
+        assert!(Scope::new(Vec::new(), Vec::new()).unwrap().is_none());
+        let scope = Scope::new(globs(&["chap*/**", "main.tex"]), globs(&["chap2/**"])).unwrap().unwrap();
+        assert!(scope.contains(Path::new("main.tex")));
+        assert!(scope.contains(Path::new("chap1/intro.tex")));
+        assert!(!scope.contains(Path::new("chap2/intro.tex")));
+        assert!(!scope.contains(Path::new("appendix.tex")));
+        let scope = Scope::new(Vec::new(), globs(&["appendix/*"])).unwrap().unwrap();
+        assert!(scope.contains(Path::new("main.tex")));
+        assert!(!scope.contains(Path::new("appendix/a.tex")));
+    }
+}
@@ -0,0 +1,153 @@
+//! 作業ツリーを含まない、リビジョン同士の差分PDFを作る。
+//! 範囲内のコミット(またはタグ)の隣り合う組ごとの差分とその一覧、stashやブランチが変えるものの差分がある。
+//! 各リビジョンは`Baseline`としてハッシュごとに一度だけ取り出し、latexdiffを直接かける。
+
+use std::path::{Path, PathBuf};
+
+use crate::{
+    baseline::Baseline,
+    cmd::latexmk::LaTeXMK,
+    cmd_for_diff,
+    difftex::DiffTex,
+    error::{self, Error},
+    in_parallel, osstr_join,
+    param::{Param, Series},
+    prepare,
+    vcs::git::Git,
+};
+
+/// 並べるリビジョンの一つ
+struct Revision {
+    hash: String,
+    /// ファイル名と一覧に使う名前(短いハッシュかタグ名)
+    name: String,
+}
+
+/// 差分PDFの隣の`series`に`<doc>-diff-<old>..<new>.pdf`を並べ、`<doc>-series.md`に一覧を書く
+pub fn run(param: &Param, series: &Series) -> error::Result<Option<i32>> {
+    let git = Git { dir: &param.dir };
+    let revisions = revisions(&git, series)?;
+    if revisions.len() < 2 {
+        println!("Nothing to compare: fewer than two revisions in the series");
+        return Ok(Some(0));
+    }
+    let baselines = revisions.iter().map(|rev| Baseline::at(param, &rev.hash)).collect::<error::Result<Vec<_>>>()?;
+    // 隣り合う組が同じツリーを取り合わないよう、展開だけは先に済ませておく
+    for baseline in &baselines {
+        baseline.extract_tree()?;
+    }
+
+    let outdir = param.diff_outdir().join("series");
+    error::create_dir_all(&outdir)?;
+    let pairs: Vec<usize> = (0..revisions.len() - 1).collect();
+    let results = in_parallel(param.jobs, &pairs, |&n| {
+        let name = format!("{}-{}..{}.pdf", param.diff_docfile.to_string_lossy(), revisions[n].name, revisions[n + 1].name);
+        diff_pdf(param, &baselines[n], &baselines[n + 1], &outdir.join(name))
+    });
+
+    let mut md = format!("# Diff series of {}\n\n| Old | New | Diff |\n|---|---|---|\n", param.docfile.to_string_lossy());
+    let describe = |n: usize| {
+        let subject = baselines[n].describe().unwrap_or_default().replace('|', "\\|");
+        format!("`{}` {subject}", revisions[n].name)
+    };
+    let mut failed = 0;
+    for (n, result) in results.into_iter().enumerate() {
+        let diff = match result {
+            Ok(pdf) => {
+                let file = pdf.file_name().unwrap_or_default().to_string_lossy().into_owned();
+                format!("[{file}]({})", file.replace(' ', "%20"))
+            }
+            Err(e) => {
+                eprintln!("Diff {}..{} failed:", revisions[n].name, revisions[n + 1].name);
+                e.print();
+                failed += 1;
+                "failed".to_string()
+            }
+        };
+        md.push_str(&format!("| {} | {} | {diff} |\n", describe(n), describe(n + 1)));
+    }
+    let index = outdir.join(osstr_join(&param.docfile, "-series.md"));
+    error::write(&index, md)?;
+    println!("Series: {} of {} diffs written ({})", pairs.len() - failed, pairs.len(), index.display());
+    Ok(Some(if failed == 0 { 0 } else { 1 }))
+}
+
+/// stashの元になったコミットからstashの内容への差分を`<doc>-diff-stash-<n>.pdf`として差分PDFの隣に置く
+pub fn stash(param: &Param, stash: &str) -> error::Result<Option<i32>> {
+    let name = stash.replace("@{", "-").replace('}', "");
+    between(param, &format!("{stash}^1"), stash, &name)
+}
+
+/// ブランチがHEADから分かれてから加えた変更を`<doc>-diff-<branch>.pdf`として差分PDFの隣に置く。チェックアウトはしない
+pub fn branch(param: &Param, branch: &str) -> error::Result<Option<i32>> {
+    let base = Git { dir: &param.dir }.merge_base("HEAD", branch)?;
+    between(param, &base, branch, &file_name(branch))
+}
+
+fn between(param: &Param, old: &str, new: &str, name: &str) -> error::Result<Option<i32>> {
+    let (old, new_side) = (Baseline::at(param, old)?, Baseline::at(param, new)?);
+    let pdf = param.diff_outdir().join(osstr_join(&param.diff_docfile, &format!("-{name}.pdf")));
+    error::create_dir_all(param.diff_outdir())?;
+    diff_pdf(param, &old, &new_side, &pdf)?;
+    println!("Changes in {new}: {}", pdf.display());
+    Ok(Some(0))
+}
+
+/// `alice/intro-rewrite`のような名前もファイル名にできるようにする
+fn file_name(rev: &str) -> String {
+    rev.replace(['/', '\\', ':', ' '], "-")
+}
+
+fn revisions(git: &Git, series: &Series) -> error::Result<Vec<Revision>> {
+    let short = |hash: &str| hash[..hash.len().min(7)].to_string();
+    match series {
+        Series::Range(range) => {
+            let mut hashes = git.rev_list(range)?;
+            // `A..B`のAそのものも最初の比較元にする(範囲は引数を読む時に`A..B`の形に揃えてある)
+            if let Some((from, _)) = range.split_once("..") {
+                hashes.insert(0, git.rev_parse(from)?);
+            }
+            Ok(hashes.into_iter().map(|hash| Revision { name: short(&hash), hash }).collect())
+        }
+        Series::Tags(pattern) => git
+            .tags(pattern)?
+            .into_iter()
+            .map(|tag| {
+                let hash = git.rev_parse(&tag)?;
+                Ok(Revision { hash, name: file_name(&tag) })
+            })
+            .collect(),
+    }
+}
+
+/// `old`から`new`への差分を`new`のツリーの中でタイプセットし、`pdf`に置く
+fn diff_pdf(param: &Param, old: &Baseline, new: &Baseline, pdf: &Path) -> error::Result<PathBuf> {
+    let workdir = param.tmpdir.join("series").join(format!("{}..{}", old.name(), new.name()));
+    error::create_dir_all(&workdir)?;
+    let diff_tex_path = workdir.join(osstr_join(&param.diff_docfile, ".tex"));
+    let (old_tex, new_tex) = prepare(param, old, new.main_tex()?, &workdir)?;
+    let latexdiff = param.latexdiff(&old_tex, &new_tex);
+    latexdiff.run(&diff_tex_path)?;
+
+    let mut diff_tex = DiffTex::read(diff_tex_path)?;
+    diff_tex.redirect_deleted_graphics(&old.extract_assets()?.graphics);
+    diff_tex.write()?;
+
+    let latexmk = LaTeXMK {
+        latexmk: &param.latexmk,
+        dir: &workdir,
+        docfile: &param.diff_docfile,
+        tmpdir: &workdir,
+        outdir: workdir.as_path().into(),
+        opts: &param.latexmk_opts,
+        pretex: None,
+    };
+    let mut cmd = latexmk.command()?;
+    // `\input`や画像は新しい側のツリーから読ませる
+    cmd.current_dir(new.extract_tree()?);
+    if cmd_for_diff(cmd, false)? != Some(0) {
+        return Err(Error::AlreadySaid);
+    }
+    error::copy(workdir.join(osstr_join(&param.diff_docfile, ".pdf")), pdf)?;
+    Ok(pdf.to_owned())
+}
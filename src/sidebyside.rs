@@ -0,0 +1,136 @@
+//! 古い版と新しい版のページを左右に並べたPDFを作る。
+//! ページ番号で揃えると一箇所の加筆で以降が全部ずれるので、両方の`.aux`にある見出しを錨にして揃える。
+
+use std::path::Path;
+
+use crate::{
+    baseline::Baseline,
+    error::{self, Error},
+    osstr_join,
+    param::Param,
+    pdf,
+    tex::aux::{Aux, Section},
+};
+
+/// 目次に書く見出しごとに、出力された物理的なページを`.aux`に書かせる。
+/// `\thepage`はローマ数字の前付けや番号の振り直しで物理的なページと合わないため(差分の`\DIFhunk`と同じ数え方)。
+/// 行は`\@gobbletwo`なので、このコードを読ませないビルドで`.aux`を読んでも何もしない
+pub const TOC_PAGE_HOOK: &str = "\\makeatletter\\ifdefined\\AddToHook\\AddToHook{cmd/addcontentsline/before}{\\protected@write\\@auxout{}{\\string\\@gobbletwo{DIFtocpage}{\\ifdefined\\ReadonlyShipoutCounter\\the\\ReadonlyShipoutCounter\\else\\the\\c@page\\fi}}}\\fi\\makeatother";
+
+/// `<doc>-sidebyside.pdf`を差分PDFの隣に作る。メインのPDFが出来上がった後に呼ぶ。
+/// 元にするPDFのどれもが前回作ったものより古ければ作り直さない。
+pub fn make(param: &Param) -> error::Result<()> {
+    let Some(baseline) = Baseline::from_param(param)? else { return Ok(()) };
+    if baseline.build()? != Some(0) {
+        return Err(Error::AlreadySaid);
+    }
+    let old_pdf = baseline.build_dir().join(osstr_join(&param.docfile, ".pdf"));
+    let new_pdf = param.outdir.join(osstr_join(&param.docfile, ".pdf"));
+    let diff_pdf = param.diff_outdir().join(osstr_join(&param.diff_docfile, ".pdf"));
+    let output = param.diff_outdir().join(osstr_join(&param.docfile, "-sidebyside.pdf"));
+    if up_to_date(&output, &[&old_pdf, &new_pdf, &diff_pdf]) {
+        return Ok(());
+    }
+    let old_aux = baseline.aux()?;
+    let new_aux = Aux::read(&param.tmpdir, &param.docfile.to_string_lossy())?;
+
+    let mut builder = pdf::Builder::new();
+    let old_pages = builder.import(&old_pdf)?;
+    let new_pages = builder.import(&new_pdf)?;
+    for (old, new) in pair_pages(&old_aux.sections, &new_aux.sections, old_pages.len(), new_pages.len()) {
+        let (old, new) = (old.map(|i| old_pages[i]), new.map(|i| new_pages[i]));
+        // 片方が無い場合は、もう片方と同じ大きさの空白にする
+        let Some(left) = old.or(new) else { continue };
+        let right = new.unwrap_or(left);
+        let height = left.height.max(right.height);
+        let mut placements = Vec::new();
+        // 上端を揃える
+        placements.extend(old.map(|form| (form, 0.0, height - form.height)));
+        placements.extend(new.map(|form| (form, left.width, height - form.height)));
+        builder.add_page(left.width + right.width, height, &placements);
+    }
+    error::create_dir_all(param.diff_outdir())?;
+    builder.save(&output)
+}
+
+/// `output`があり、`inputs`のうち存在するものすべてより新しいか
+fn up_to_date(output: &Path, inputs: &[&Path]) -> bool {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let Some(made) = modified(output) else { return false };
+    inputs.iter().filter_map(|input| modified(input)).all(|input| input <= made)
+}
+
+/// 左右に並べるページの組(0始まり)。相手の無いページは`None`と組になる。
+fn pair_pages(old: &[Section], new: &[Section], old_len: usize, new_len: usize) -> Vec<(Option<usize>, Option<usize>)> {
+    let mut pairs = Vec::new();
+    let (mut o, mut n) = (0, 0);
+    for (anchor_o, anchor_n) in common_sections(old, new).into_iter().chain([(old_len, new_len)]) {
+        if anchor_o < o || anchor_n < n || anchor_o > old_len || anchor_n > new_len {
+            continue; // 順序が入れ替わった見出しは錨にしない
+        }
+        // 錨までは先頭から順に組にし、余った側は空白と組にする
+        while o < anchor_o || n < anchor_n {
+            pairs.push(((o < anchor_o).then_some(o), (n < anchor_n).then_some(n)));
+            o += usize::from(o < anchor_o);
+            n += usize::from(n < anchor_n);
+        }
+    }
+    pairs
+}
+
+/// 両方にある見出し(最長共通部分列)のページ位置の組。
+fn common_sections(old: &[Section], new: &[Section]) -> Vec<(usize, usize)> {
+    let same = |a: &Section, b: &Section| a.level == b.level && a.title == b.title;
+    // lcs[i][j]: old[i..]とnew[j..]の最長共通部分列の長さ
+    let mut lcs = vec![vec![0u32; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if same(&old[i], &new[j]) { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+        }
+    }
+    // 物理的なページが分からなければ`\thepage`で代用する
+    let page = |s: &Section| s.abs_page.or_else(|| s.page.trim().parse().ok()).and_then(|p| p.checked_sub(1));
+    let mut anchors = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if same(&old[i], &new[j]) {
+            if let (Some(o), Some(n)) = (page(&old[i]), page(&new[j])) {
+                anchors.push((o, n));
+            }
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    anchors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sections(list: &[(&str, &str, Option<usize>)]) -> Vec<Section> {
+        list.iter().map(|&(title, page, abs_page)| Section { level: "section".to_string(), title: title.to_string(), page: page.to_string(), abs_page }).collect()
+    }
+
+    #[test]
+    fn pairs_pages_at_common_sections() {
+        let old = sections(&[("Intro", "1", None), ("Method", "3", None)]);
+        let new = sections(&[("Intro", "1", None), ("Added", "2", None), ("Method", "4", None)]);
+        assert_eq!(pair_pages(&old, &new, 4, 5), [(Some(0), Some(0)), (Some(1), Some(1)), (None, Some(2)), (Some(2), Some(3)), (Some(3), Some(4))]);
+        // 入れ替わった見出しは片方だけを錨にし、錨までのページの多い側は空白と組にする
+        let new = sections(&[("Method", "1", None), ("Intro", "2", None)]);
+        assert_eq!(pair_pages(&old, &new, 3, 2), [(Some(0), None), (Some(1), None), (Some(2), Some(0)), (None, Some(1))]);
+    }
+
+    #[test]
+    fn prefers_physical_pages() {
+        // 新しい側だけローマ数字の前付けが二ページあり、本文でページ番号を振り直している
+        let old = sections(&[("Intro", "1", Some(1)), ("Method", "2", Some(2))]);
+        let new = sections(&[("Preface", "i", Some(1)), ("Intro", "1", Some(3)), ("Method", "2", Some(4))]);
+        assert_eq!(pair_pages(&old, &new, 2, 4), [(None, Some(0)), (None, Some(1)), (Some(0), Some(2)), (Some(1), Some(3))]);
+    }
+}
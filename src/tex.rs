@@ -0,0 +1,548 @@
+//! LaTeXソースを軽く読むための道具。
+//! 完全な字句解析はせず、コメントと括弧の対応だけを見てコマンドとその引数を拾う。
+
+pub mod aux;
+pub mod bib;
+pub mod synctex;
+
+use std::{
+    collections::HashSet,
+    path::{Component, Path, PathBuf},
+};
+
+/// ソース中で見つかったコマンド呼び出し。`start..end`はコマンド名の`\`から最後の引数の閉じ括弧まで。
+#[derive(Debug, Clone)]
+pub struct Cmd<'a> {
+    pub name: &'a str,
+    pub start: usize,
+    pub end: usize,
+    pub opt: Option<&'a str>,
+    pub args: Vec<&'a str>,
+    /// 各必須引数の中身の位置(括弧は含まない)
+    pub arg_ranges: Vec<std::ops::Range<usize>>,
+}
+
+/// `names`のいずれかに一致するコマンドを、`nargs`個の必須引数付きで探す。
+/// `\cmd*`の星と、必須引数の前の`[...]`は読み飛ばす(`\citep[see][p.~3]{key}`のように続いてもよく、最後のもの、つまり後置きの中身が`opt`に入る)。
+/// 引数無しなら`[...]`は読まない。
+pub fn commands<'a>(src: &'a str, names: &[&str], nargs: usize) -> Vec<Cmd<'a>> {
+    let bytes = src.as_bytes();
+    let mut found = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => i = line_end(bytes, i),
+            b'\\' => {
+                let name_end = control_word_end(bytes, i + 1);
+                if name_end == i + 1 {
+                    // `\%`や`\\`などの一文字コマンドは次の文字ごと飛ばす
+                    i += 2;
+                    continue;
+                }
+                let name = &src[i + 1..name_end];
+                if names.contains(&name)
+                    && let Some(cmd) = parse_args(src, i, name, name_end, nargs)
+                {
+                    i = cmd.end;
+                    found.push(cmd);
+                    continue;
+                }
+                i = name_end;
+            }
+            _ => i += 1,
+        }
+    }
+    found
+}
+
+fn parse_args<'a>(src: &'a str, start: usize, name: &'a str, mut i: usize, nargs: usize) -> Option<Cmd<'a>> {
+    let bytes = src.as_bytes();
+    if bytes.get(i) == Some(&b'*') {
+        i += 1;
+    }
+    let mut opt = None;
+    let mut after_ws = skip_ws(bytes, i);
+    while nargs > 0 && bytes.get(after_ws) == Some(&b'[') {
+        let close = group_end(bytes, after_ws, b'[', b']')?;
+        opt = Some(&src[after_ws + 1..close]);
+        i = close + 1;
+        after_ws = skip_ws(bytes, i);
+    }
+    let mut args = Vec::with_capacity(nargs);
+    let mut arg_ranges = Vec::with_capacity(nargs);
+    for _ in 0..nargs {
+        i = skip_ws(bytes, i);
+        if bytes.get(i) != Some(&b'{') {
+            return None;
+        }
+        let close = group_end(bytes, i, b'{', b'}')?;
+        args.push(&src[i + 1..close]);
+        arg_ranges.push(i + 1..close);
+        i = close + 1;
+    }
+    Some(Cmd { name, start, end: i, opt, args, arg_ranges })
+}
+
+fn control_word_end(bytes: &[u8], mut i: usize) -> usize {
+    while i < bytes.len() && bytes[i].is_ascii_alphabetic() {
+        i += 1;
+    }
+    i
+}
+fn line_end(bytes: &[u8], i: usize) -> usize {
+    bytes[i..].iter().position(|&b| b == b'\n').map_or(bytes.len(), |p| i + p)
+}
+fn skip_ws(bytes: &[u8], mut i: usize) -> usize {
+    while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+        i += 1;
+    }
+    i
+}
+
+/// `bytes[open]`にある開き括弧に対応する閉じ括弧の位置を返す。エスケープとコメントは考慮する。
+pub fn group_end(bytes: &[u8], open: usize, left: u8, right: u8) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut i = open;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 1,
+            b'%' => i = line_end(bytes, i),
+            b if b == left => depth += 1,
+            b if b == right => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// 先頭から連続する`{...}`をすべて取り出す(`{{1}{2}}`の中身に対して使うと`["1", "2"]`)。
+pub fn groups(src: &str) -> Vec<&str> {
+    let bytes = src.as_bytes();
+    let mut found = Vec::new();
+    let mut i = skip_ws(bytes, 0);
+    while bytes.get(i) == Some(&b'{') {
+        let Some(close) = group_end(bytes, i, b'{', b'}') else { break };
+        found.push(&src[i + 1..close]);
+        i = skip_ws(bytes, close + 1);
+    }
+    found
+}
+
+/// 先頭から`n`個の`{...}`を読み飛ばした残り
+pub fn after_groups(src: &str, n: usize) -> &str {
+    let bytes = src.as_bytes();
+    let mut i = skip_ws(bytes, 0);
+    for _ in 0..n {
+        if bytes.get(i) != Some(&b'{') {
+            break;
+        }
+        let Some(close) = group_end(bytes, i, b'{', b'}') else { break };
+        i = close + 1;
+    }
+    &src[i..]
+}
+
+/// 行からコメント部分(エスケープされていない`%`以降)を取り除く。
+pub fn strip_comment(line: &str) -> &str {
+    let bytes = line.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'%' => return &line[..i],
+            _ => i += 1,
+        }
+    }
+    line
+}
+
+/// `a,b, c`のようなカンマ区切りの引数を分解する。
+pub fn split_list(arg: &str) -> impl Iterator<Item = &str> {
+    arg.split(',').map(str::trim).filter(|s| !s.is_empty())
+}
+
+/// `..`や`.`を字面上で畳み込む。ファイルシステムには触らない。
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for comp in path.components() {
+        match comp {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !out.pop() {
+                    out.push("..");
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// `\input`や`\include`で取り込まれたソースを一つに展開したもの。
+/// 行ごとにどのファイルの何行目から来たかを覚えておく。
+#[derive(Debug, Default)]
+pub struct Flattened {
+    pub lines: Vec<SourceLine>,
+    /// 読み込みに成功したファイル(文書ディレクトリからの相対パス)
+    pub files: Vec<PathBuf>,
+}
+#[derive(Debug, Clone)]
+pub struct SourceLine {
+    pub file: PathBuf,
+    /// 1始まりの行番号
+    pub line: usize,
+    pub text: String,
+}
+impl Flattened {
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        for line in &self.lines {
+            text.push_str(&line.text);
+            text.push('\n');
+        }
+        text
+    }
+}
+
+const INPUT_CMDS: &[&str] = &["input", "include", "subfile"];
+
+/// `main`(文書ディレクトリからの相対パス)を起点に`\input`類を展開する。
+/// `read`は文書ディレクトリからの相対パスを受け取り、ファイルの中身を返す(無ければ`None`)。
+/// 作業ツリーでも過去のリビジョンでも同じように扱えるように読み出しは外から渡す。
+pub fn flatten(main: &Path, read: &mut dyn FnMut(&Path) -> Option<String>) -> Flattened {
+    let mut flat = Flattened::default();
+    let mut visiting = HashSet::new();
+    flatten_into(main, read, &mut flat, &mut visiting);
+    flat
+}
+
+fn flatten_into(file: &Path, read: &mut dyn FnMut(&Path) -> Option<String>, flat: &mut Flattened, visiting: &mut HashSet<PathBuf>) {
+    let file = normalize(file);
+    // TeXと同様、拡張子無しならまず`.tex`を補う
+    let (file, src) = match read_tex(&file, read) {
+        Some(found) => found,
+        None => return,
+    };
+    if !visiting.insert(file.clone()) {
+        return; // 循環している
+    }
+    flat.files.push(file.clone());
+    for (n, line) in src.lines().enumerate() {
+        let mut rest = line;
+        loop {
+            let code = strip_comment(rest);
+            let Some(cmd) = commands(code, INPUT_CMDS, 1).into_iter().next() else {
+                flat.lines.push(SourceLine { file: file.clone(), line: n + 1, text: rest.to_string() });
+                break;
+            };
+            if !code[..cmd.start].trim().is_empty() {
+                flat.lines.push(SourceLine { file: file.clone(), line: n + 1, text: code[..cmd.start].to_string() });
+            }
+            flatten_into(Path::new(cmd.args[0].trim()), read, flat, visiting);
+            rest = &rest[cmd.end..];
+            if rest.trim().is_empty() {
+                break;
+            }
+        }
+    }
+    visiting.remove(&file);
+}
+
+fn read_tex(file: &Path, read: &mut dyn FnMut(&Path) -> Option<String>) -> Option<(PathBuf, String)> {
+    if file.extension().is_none() {
+        let with_ext = file.with_extension("tex");
+        if let Some(src) = read(&with_ext) {
+            return Some((with_ext, src));
+        }
+    }
+    read(file).map(|src| (file.to_path_buf(), src))
+}
+
+/// 作業ツリー上のファイルを読む`flatten`用の読み出し関数。
+pub fn read_from_dir(dir: &Path) -> impl FnMut(&Path) -> Option<String> + '_ {
+    move |path| std::fs::read(dir.join(path)).ok().map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// ラベルを参照するコマンド(cleverefとhyperrefのものを含む)
+pub const REF_CMDS: &[&str] = &["ref", "eqref", "pageref", "cref", "Cref", "autoref", "Autoref"];
+/// 文献を番号で引用するコマンド(natbibとbiblatexのものを含む)
+pub const CITE_CMDS: &[&str] = &[
+    "cite", "citep", "citealp", "Cite", "Citep", "parencite", "Parencite", "autocite", "Autocite", "footcite", "smartcite",
+    "supercite",
+];
+/// 文献を著者名で引用するコマンド
+pub const TEXTUAL_CITE_CMDS: &[&str] = &["citet", "citealt", "Citet", "textcite", "Textcite"];
+/// そのほかに文献のキーを取るコマンド
+pub const OTHER_CITE_CMDS: &[&str] = &["citeauthor", "citeyear", "citeyearpar", "fullcite", "footcitetext", "nocite"];
+
+/// 引数ごと読み捨てるコマンド(本文として読まれない引数を持つもの)。参照と引用のコマンドも読み捨てる
+const SKIP_WITH_ARGS: &[&str] = &[
+    "label", "includegraphics", "input", "include", "bibliography", "bibliographystyle", "usepackage", "documentclass",
+    "begin", "end", "url", "DIFdel", "DIFdelFL", "DIFhunk", "DIFblame", "DIFoldref", "DIFoldcite", "hspace", "vspace",
+];
+
+/// 読める文字だけを取り出す。コマンド名と括弧は捨て、本文になる引数の中身は残す。
+/// 数式はそのまま残す。空白は一つにまとめる。
+pub fn plain_text(src: &str) -> String {
+    let bytes = src.as_bytes();
+    let mut out = String::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => i = line_end(bytes, i),
+            b'\\' => {
+                let name_end = control_word_end(bytes, i + 1);
+                if name_end == i + 1 {
+                    // `\%`などは文字として、`\\`は改行として扱う
+                    match bytes.get(i + 1) {
+                        Some(b'\\') | None => out.push(' '),
+                        Some(_) => out.push_str(&src[i + 1..i + 1 + utf8_len(bytes[i + 1])]),
+                    }
+                    i += 1 + bytes.get(i + 1).map_or(0, |&b| utf8_len(b));
+                    continue;
+                }
+                let name = &src[i + 1..name_end];
+                i = name_end;
+                if [SKIP_WITH_ARGS, REF_CMDS, CITE_CMDS, TEXTUAL_CITE_CMDS, OTHER_CITE_CMDS].iter().any(|cmds| cmds.contains(&name)) {
+                    // `\cite[p.~3]{key}`のような引数を全部飛ばす
+                    if bytes.get(i) == Some(&b'*') {
+                        i += 1;
+                    }
+                    loop {
+                        let j = skip_ws(bytes, i);
+                        let close = match bytes.get(j) {
+                            Some(b'{') => group_end(bytes, j, b'{', b'}'),
+                            Some(b'[') => group_end(bytes, j, b'[', b']'),
+                            _ => None,
+                        };
+                        let Some(close) = close else { break };
+                        i = close + 1;
+                    }
+                } else {
+                    out.push(' ');
+                }
+            }
+            b'{' | b'}' => i += 1,
+            b'~' => {
+                out.push(' ');
+                i += 1;
+            }
+            _ => {
+                let len = utf8_len(bytes[i]);
+                out.push_str(&src[i..i + len]);
+                i += len;
+            }
+        }
+    }
+    collapse_ws(&out)
+}
+
+/// 空白の並びを一つの空白にまとめ、前後の空白を取り除く。
+pub fn collapse_ws(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// 中身をそのまま組む環境
+pub const VERBATIM_ENVS: &[&str] = &["verbatim", "Verbatim", "lstlisting", "minted", "comment"];
+
+/// [`VERBATIM_ENVS`]の環境と`\verb`の位置(`\begin`や`\verb`から閉じるところまで)。閉じていなければソースの終わりまで
+pub fn verbatim_regions(src: &str) -> Vec<std::ops::Range<usize>> {
+    let mut regions: Vec<std::ops::Range<usize>> = Vec::new();
+    for cmd in commands(src, &["begin"], 1) {
+        let env = cmd.args[0].trim();
+        if !VERBATIM_ENVS.contains(&env) || regions.last().is_some_and(|r| r.contains(&cmd.start)) {
+            continue;
+        }
+        let end = format!("\\end{{{env}}}");
+        regions.push(cmd.start..src[cmd.end..].find(&end).map_or(src.len(), |p| cmd.end + p + end.len()));
+    }
+    let bytes = src.as_bytes();
+    let mut verbs = Vec::new();
+    let mut from = 0;
+    while let Some(p) = src[from..].find("\\verb") {
+        let start = from + p;
+        let mut i = start + "\\verb".len();
+        from = i;
+        // `\verbatim`などの別のコマンドや、環境の中のものは数えない
+        if bytes.get(i).is_some_and(u8::is_ascii_alphabetic) || regions.iter().any(|r| r.contains(&start)) {
+            continue;
+        }
+        if bytes.get(i) == Some(&b'*') {
+            i += 1;
+        }
+        // 区切りは同じ行の次の同じ文字まで
+        let Some(&delim) = bytes.get(i).filter(|d| d.is_ascii_graphic()) else { continue };
+        let close = bytes[i + 1..line_end(bytes, i)].iter().position(|&b| b == delim).map_or(line_end(bytes, i), |p| i + 1 + p + 1);
+        verbs.push(start..close);
+        from = close;
+    }
+    regions.extend(verbs);
+    regions.sort_by_key(|r| r.start);
+    regions
+}
+
+/// 地の文として書けるように特殊文字をエスケープする
+pub fn escape(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '_' | '&' | '%' | '#' | '$' | '{' | '}' => format!("\\{c}"),
+            '\\' => "\\textbackslash{}".to_string(),
+            '^' | '~' => format!("\\{c}{{}}"),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+/// 拡張子が無ければ付ける
+pub fn with_ext(name: &str, ext: &str) -> String {
+    if name.ends_with(&format!(".{ext}")) { name.to_string() } else { format!("{name}.{ext}") }
+}
+
+/// 文書が読み込んでいる`.bib`のパス(文書ディレクトリからの相対パス)
+pub fn bib_files(src: &str) -> Vec<String> {
+    let cmds = commands(src, &["bibliography", "addbibresource", "addglobalbib"], 1);
+    cmds.iter().flat_map(|cmd| split_list(cmd.args[0])).map(|name| with_ext(name, "bib")).collect()
+}
+
+const NEWCOMMAND_CMDS: &[&str] = &["newcommand", "renewcommand", "providecommand", "DeclareRobustCommand", "DeclareMathOperator"];
+const DEF_CMDS: &[&str] = &["def", "gdef", "edef", "xdef"];
+
+/// `\newcommand`類や`\def`によるマクロの定義
+#[derive(Debug, Clone)]
+pub struct Definition<'a> {
+    /// 定義に使ったコマンド(`newcommand`、`def`など)
+    pub command: &'a str,
+    /// `\`を除いた名前
+    pub name: &'a str,
+    /// 定義全体の位置
+    pub range: std::ops::Range<usize>,
+    pub nargs: usize,
+    /// 最初の引数を省略したときの値
+    pub default: Option<&'a str>,
+    pub body: &'a str,
+    /// `\def\a#1.{...}`のような区切り付きの引数を取る
+    pub delimited: bool,
+}
+
+/// `src`にあるマクロの定義を現れる順に返す。名前を括弧で囲まない`\newcommand\a{...}`は読まない
+pub fn definitions(src: &str) -> Vec<Definition<'_>> {
+    let bytes = src.as_bytes();
+    let mut found = Vec::new();
+    for cmd in commands(src, NEWCOMMAND_CMDS, 1) {
+        let Some(name) = cmd.args[0].trim().strip_prefix('\\') else { continue };
+        // `[引数の数][省略時の値]{本体}`
+        let mut i = skip_ws(bytes, cmd.end);
+        let mut opts = Vec::new();
+        while bytes.get(i) == Some(&b'[') {
+            let Some(close) = group_end(bytes, i, b'[', b']') else { break };
+            opts.push(&src[i + 1..close]);
+            i = skip_ws(bytes, close + 1);
+        }
+        if bytes.get(i) != Some(&b'{') {
+            continue;
+        }
+        let Some(close) = group_end(bytes, i, b'{', b'}') else { continue };
+        let nargs = opts.first().and_then(|n| n.trim().parse().ok()).unwrap_or(0);
+        let (command, body) = (cmd.name, &src[i + 1..close]);
+        found.push(Definition { command, name, range: cmd.start..close + 1, nargs, default: opts.get(1).copied(), body, delimited: false });
+    }
+    for cmd in commands(src, DEF_CMDS, 0) {
+        // `\def\name#1#2{...}`
+        if bytes.get(cmd.end) != Some(&b'\\') {
+            continue;
+        }
+        let name_end = control_word_end(bytes, cmd.end + 1);
+        if name_end == cmd.end + 1 {
+            continue; // `\def\%`のような一文字のもの
+        }
+        let Some(open) = src[name_end..].find('{').map(|p| name_end + p) else { continue };
+        let Some(close) = group_end(bytes, open, b'{', b'}') else { continue };
+        let params = src[name_end..open].trim();
+        let nargs = params.matches('#').count();
+        let delimited = params != (1..=nargs).map(|n| format!("#{n}")).collect::<String>();
+        let (name, body) = (&src[cmd.end + 1..name_end], &src[open + 1..close]);
+        found.push(Definition { command: cmd.name, name, range: cmd.start..close + 1, nargs, default: None, body, delimited });
+    }
+    found.sort_by_key(|def| def.range.start);
+    found
+}
+
+/// 語数。空白で区切られた語を数え、和文は(texcountと同じく)一文字を一語とする。記号だけのものは数えない
+pub fn word_count(text: &str) -> usize {
+    let cjk = |c: char| matches!(c, '\u{3040}'..='\u{30ff}' | '\u{3400}'..='\u{4dbf}' | '\u{4e00}'..='\u{9fff}' | '\u{f900}'..='\u{faff}' | '\u{ac00}'..='\u{d7af}');
+    text.split_whitespace()
+        .map(|word| word.chars().filter(|&c| cjk(c)).count() + usize::from(word.chars().any(|c| c.is_alphanumeric() && !cjk(c))))
+        .sum()
+}
+
+fn utf8_len(first: u8) -> usize {
+    match first {
+        0xF0.. => 4,
+        0xE0.. => 3,
+        0xC0.. => 2,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skips_every_optional_group() {
+        let cmds = commands("\\citep[see][p.~3]{a,b} \\cite [x] {c} \\citep{d}", &["cite", "citep"], 1);
+        let found: Vec<(&str, Option<&str>, &str)> = cmds.iter().map(|cmd| (cmd.name, cmd.opt, cmd.args[0])).collect();
+        assert_eq!(found, vec![("citep", Some("p.~3"), "a,b"), ("cite", Some("x"), "c"), ("citep", None, "d")]);
+    }
+
+    #[test]
+    fn ignores_comments_and_escaped_percent() {
+        let src = "\\ref{a} % \\ref{b}\n50\\% \\ref{c}";
+        let labels: Vec<&str> = commands(src, &["ref"], 1).iter().map(|cmd| cmd.args[0]).collect();
+        assert_eq!(labels, vec!["a", "c"]);
+        assert_eq!(strip_comment("50\\% done % note"), "50\\% done ");
+    }
+
+    #[test]
+    fn reads_definitions() {
+        let src = "\\newcommand{\\R}{\\mathbb{R}}\n\\renewcommand{\\v}[2][x]{#1_#2}\n\\def\\pair#1#2{(#1,#2)}\\def\\d#1.{#1}\\def\\%{}";
+        let defs: Vec<(&str, usize, Option<&str>, &str, bool)> =
+            definitions(src).iter().map(|d| (d.name, d.nargs, d.default, d.body, d.delimited)).collect();
+        assert_eq!(
+            defs,
+            vec![
+                ("R", 0, None, "\\mathbb{R}", false),
+                ("v", 2, Some("x"), "#1_#2", false),
+                ("pair", 2, None, "(#1,#2)", false),
+                ("d", 1, None, "#1", true),
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_at_cyclic_inputs() {
+        let files = [("main.tex", "a\n\\input{sub}\nb\n"), ("sub.tex", "s\n\\input{main}\n")];
+        let mut read = |path: &Path| files.iter().find(|(name, _)| Path::new(name) == path).map(|(_, src)| src.to_string());
+        let flat = flatten(Path::new("main"), &mut read);
+        assert_eq!(flat.files, vec![PathBuf::from("main.tex"), PathBuf::from("sub.tex")]);
+    }
+
+    #[test]
+    fn counts_cjk_characters_as_words() {
+        assert_eq!(word_count("two words 日本語 x-y"), 6);
+        assert_eq!(word_count("-- ,"), 0);
+    }
+
+    #[test]
+    fn finds_verbatim_regions() {
+        let src = "a \\verb|\\x| b \\verbatiminput{f}\n\\begin{lstlisting}\n\\verb|y|\n\\end{lstlisting} c \\verb+open\nd";
+        let regions: Vec<&str> = verbatim_regions(src).into_iter().map(|r| &src[r]).collect();
+        assert_eq!(regions, ["\\verb|\\x|", "\\begin{lstlisting}\n\\verb|y|\n\\end{lstlisting}", "\\verb+open"]);
+    }
+}
@@ -0,0 +1,196 @@
+//! `.aux`と`.bbl`から、ラベルと文献の番号を拾う。
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use crate::{error, tex};
+
+#[derive(Debug, Clone, Default)]
+pub struct Label {
+    pub number: String,
+    pub page: String,
+    /// `figure`や`equation`など。cleverefかhyperrefが書いたものがあれば分かる
+    pub kind: Option<String>,
+}
+
+/// 目次に書き出された見出し
+#[derive(Debug, Clone)]
+pub struct Section {
+    /// `section`や`subsection`など
+    pub level: String,
+    /// 番号(`\numberline`)を除いた見出し
+    pub title: String,
+    pub page: String,
+    /// 先頭から数えたページ番号(1始まり)。`--side-by-side`のビルドが書いた場合だけ分かる
+    pub abs_page: Option<usize>,
+}
+
+/// 差分の`\DIFhunk{n}`が出力されたページ
+#[derive(Debug, Clone)]
+pub struct HunkPage {
+    /// `\thepage`の表記
+    pub page: String,
+    /// 先頭から数えたページ番号(1始まり)
+    pub abs_page: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Aux {
+    pub labels: HashMap<String, Label>,
+    /// 文書順の見出し
+    pub sections: Vec<Section>,
+    /// 差分の通し番号から、それが出力されたページへ
+    pub hunks: BTreeMap<usize, HunkPage>,
+    /// 文献のキーから表示される番号(ラベル)へ
+    pub cites: HashMap<String, String>,
+    /// 文献のキーから短い著者名へ。natbibが書いた場合だけ分かる
+    pub cite_authors: HashMap<String, String>,
+}
+impl Aux {
+    /// `dir`にある`<jobname>.aux`(と、そこから`\@input`されている`.aux`)と`<jobname>.bbl`を読む。
+    pub fn read(dir: &Path, jobname: &str) -> error::Result<Aux> {
+        let mut aux = Aux::default();
+        aux.read_aux(dir, &format!("{jobname}.aux"), 0)?;
+        if let Ok(bbl) = std::fs::read(dir.join(format!("{jobname}.bbl"))) {
+            aux.parse_bbl(&String::from_utf8_lossy(&bbl));
+        }
+        Ok(aux)
+    }
+
+    fn read_aux(&mut self, dir: &Path, file: &str, depth: usize) -> error::Result<()> {
+        let src = error::read(dir.join(file))?;
+        self.parse_aux(&String::from_utf8_lossy(&src), &mut |aux, child| {
+            // \includeされたファイルの.auxは無いこともある
+            if depth < 16 {
+                let _ = aux.read_aux(dir, child, depth + 1);
+            }
+        });
+        Ok(())
+    }
+
+    /// `.aux`を一つ読む。`\@input`されたものはその場で`input`に読ませる(見出しの順を保つため)
+    fn parse_aux(&mut self, src: &str, input: &mut dyn FnMut(&mut Aux, &str)) {
+        // 直後の`\@writefile`が出力されたページ(sidebyside::TOC_PAGE_HOOK)
+        let mut toc_page = None;
+        for line in src.lines() {
+            if let Some(rest) = line.strip_prefix("\\newlabel") {
+                if let [key, value, ..] = tex::groups(rest)[..] {
+                    let fields = tex::groups(value);
+                    if let Some(key) = key.strip_suffix("@cref") {
+                        // cleveref: \newlabel{key@cref}{{[figure][3][]3}{[1][2][]2}}
+                        let kind = fields.first().and_then(|f| f.strip_prefix('[')).and_then(|f| f.split_once(']')).map(|(kind, _)| kind.to_string());
+                        self.labels.entry(key.to_string()).or_default().kind = kind;
+                        continue;
+                    }
+                    let field = |n: usize| fields.get(n).map_or(String::new(), |f| f.to_string());
+                    let label = self.labels.entry(key.to_string()).or_default();
+                    (label.number, label.page) = (field(0), field(1));
+                    // hyperref: 4番目がアンカー(`figure.caption.3`や`equation.2.1`)
+                    if label.kind.is_none() && !field(3).is_empty() {
+                        label.kind = field(3).split('.').next().map(str::to_string);
+                    }
+                }
+            } else if let Some(rest) = line.strip_prefix("\\bibcite") {
+                if let [key, value, ..] = tex::groups(rest)[..] {
+                    // natbibは`{{1}{2020}{{著者}}{{}}}`の形で書く
+                    let fields = tex::groups(value);
+                    let number = fields.first().copied().unwrap_or(value);
+                    self.cites.insert(key.to_string(), number.to_string());
+                    if let Some(author) = fields.get(2) {
+                        let author = tex::groups(author).first().copied().unwrap_or(author);
+                        self.cite_authors.insert(key.to_string(), author.to_string());
+                    }
+                }
+            } else if let Some(rest) = line.strip_prefix("\\abx@aux@number") {
+                // biblatex: \abx@aux@number{refsection}{key}{refcontext...}{number}(番号は最後の引数)
+                if let [_, key, _, .., number] = tex::groups(rest)[..] {
+                    self.cites.entry(key.to_string()).or_insert_with(|| number.to_string());
+                }
+            } else if let Some(rest) = line.strip_prefix("\\@gobbletwo{DIFtocpage}") {
+                toc_page = tex::groups(rest).first().and_then(|page| page.parse().ok());
+            } else if let Some(rest) = line.strip_prefix("\\@writefile")
+                && let abs_page = toc_page.take()
+                && let [file, entry, ..] = tex::groups(rest)[..]
+                && file == "toc"
+                && let Some(entry) = entry.trim_start().strip_prefix("\\contentsline")
+                && let [level, title, page, ..] = tex::groups(entry)[..]
+            {
+                let title = match title.trim_start().strip_prefix("\\numberline") {
+                    Some(numbered) => tex::after_groups(numbered, 1),
+                    None => title,
+                };
+                let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+                self.sections.push(Section { level: level.to_string(), title, page: page.to_string(), abs_page });
+            } else if let Some(rest) = line.strip_prefix("\\DIFhunkpage")
+                && let [id, page, abs_page] = tex::groups(rest)[..]
+                && let (Ok(id), Ok(abs_page)) = (id.parse(), abs_page.parse())
+            {
+                // 複数回書かれたら(目次などで)最初のものを使う
+                self.hunks.entry(id).or_insert_with(|| HunkPage { page: page.to_string(), abs_page });
+            } else if let Some(rest) = line.strip_prefix("\\@input")
+                && let Some(child) = tex::groups(rest).first()
+            {
+                input(self, child);
+            }
+        }
+    }
+
+    /// BibTeXの番号は`\bibcite`に書かれるが、無い場合は`.bbl`の並び順で補う
+    fn parse_bbl(&mut self, bbl: &str) {
+        for (n, item) in tex::commands(bbl, &["bibitem", "entry"], 1).iter().enumerate() {
+            self.cites.entry(item.args[0].trim().to_string()).or_insert_with(|| (n + 1).to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_labels_cites_sections_and_hunks() {
+        let main = r"\relax
+\newlabel{fig:a@cref}{{[figure][3][]3}{[1][2][]2}}
+\newlabel{fig:a}{{3}{2}{Caption}{figure.caption.3}{}}
+\newlabel{eq:b}{{1.2}{4}{}{equation.1.2}{}}
+\bibcite{natbib}{{1}{2020}{{Smith et~al.}}{{Smith, Jones and Lee}}}
+\bibcite{plain}{7}
+\abx@aux@number{0}{blx}{none/global//global/global}{1}
+\@gobbletwo{DIFtocpage}{3}
+\@writefile{toc}{\contentsline {section}{\numberline {1}Intro\ duction}{1}{section.1}}
+\@writefile{toc}{\contentsline {subsection}{Unnumbered}{2}{}}
+\DIFhunkpage{2}{ii}{2}
+\DIFhunkpage{2}{iii}{3}
+\@input{chap.aux}
+\@input{missing.aux}
+\@writefile{toc}{\contentsline {section}{Last}{4}{}}
+";
+        let mut aux = Aux::default();
+        let mut inputs = Vec::new();
+        aux.parse_aux(main, &mut |aux, child| {
+            inputs.push(child.to_string());
+            if child == "chap.aux" {
+                aux.parse_aux("\\newlabel{sec:c}{{2}{5}}\n\\@writefile{toc}{\\contentsline {section}{Chapter}{3}{}}\n", &mut |_, _| {});
+            }
+        });
+        aux.parse_bbl("\\bibitem{plain} P\n\\bibitem{other} O\n");
+        assert_eq!(inputs, ["chap.aux", "missing.aux"]);
+        let fig = &aux.labels["fig:a"];
+        assert_eq!((fig.number.as_str(), fig.page.as_str(), fig.kind.as_deref()), ("3", "2", Some("figure")));
+        assert_eq!(aux.labels["eq:b"].kind.as_deref(), Some("equation"));
+        assert_eq!(aux.labels["sec:c"].number, "2");
+        assert_eq!(aux.cites["natbib"], "1");
+        assert_eq!(aux.cite_authors["natbib"], "Smith et~al.");
+        // `\bibcite`があれば`.bbl`の並びより優先する
+        assert_eq!((aux.cites["plain"].as_str(), aux.cites["other"].as_str()), ("7", "2"));
+        assert_eq!(aux.cites["blx"], "1");
+        let sections: Vec<(&str, &str)> = aux.sections.iter().map(|s| (s.level.as_str(), s.title.as_str())).collect();
+        // `\@input`された`.aux`の見出しはその位置に入る
+        assert_eq!(sections, [("section", "Intro\\ duction"), ("subsection", "Unnumbered"), ("section", "Chapter"), ("section", "Last")]);
+        // 物理的なページは直後の見出しにだけ付く
+        assert_eq!(aux.sections.iter().map(|s| s.abs_page).collect::<Vec<_>>(), [Some(3), None, None, None]);
+        assert_eq!((aux.hunks[&2].page.as_str(), aux.hunks[&2].abs_page), ("ii", 2));
+    }
+}
@@ -0,0 +1,130 @@
+//! `.bib`を項目とフィールドに分けるだけの読み取り。`@string`の展開などはしない。
+
+use std::collections::BTreeMap;
+
+use crate::tex;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// `article`など(小文字)
+    pub kind: String,
+    pub key: String,
+    /// フィールド名(小文字)から値へ。値は外側の括弧や引用符を外し、空白をまとめたもの
+    pub fields: BTreeMap<String, String>,
+}
+
+pub fn parse(src: &str) -> Vec<Entry> {
+    let bytes = src.as_bytes();
+    let mut entries = Vec::new();
+    let mut i = 0;
+    while let Some(at) = src[i..].find('@') {
+        let start = i + at + 1;
+        let kind_end = start + src[start..].find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(src.len() - start);
+        let kind = src[start..kind_end].to_lowercase();
+        let open = kind_end + src[kind_end..].len() - src[kind_end..].trim_start().len();
+        let close = match bytes.get(open) {
+            Some(b'{') => brace_end(bytes, open, b'{', b'}'),
+            Some(b'(') => brace_end(bytes, open, b'(', b')'),
+            _ => None,
+        };
+        let Some(close) = close else {
+            i = start;
+            continue;
+        };
+        i = close + 1;
+        if kind.is_empty() || ["comment", "string", "preamble"].contains(&kind.as_str()) {
+            continue;
+        }
+        let body = &src[open + 1..close];
+        let Some((key, fields)) = body.split_once(',') else { continue };
+        entries.push(Entry { kind, key: key.trim().to_string(), fields: parse_fields(fields) });
+    }
+    entries
+}
+
+fn parse_fields(src: &str) -> BTreeMap<String, String> {
+    let bytes = src.as_bytes();
+    let mut fields = BTreeMap::new();
+    let mut i = 0;
+    while let Some(eq) = src[i..].find('=') {
+        let name = src[i..i + eq].trim().trim_start_matches(',').trim().to_lowercase();
+        let mut j = i + eq + 1;
+        let mut value = String::new();
+        // `"a" # b # {c}`のような連結も読む
+        loop {
+            j += src[j..].len() - src[j..].trim_start().len();
+            let end = match bytes.get(j) {
+                Some(b'{') => brace_end(bytes, j, b'{', b'}').map(|e| (j + 1, e, e + 1)),
+                Some(b'"') => src[j + 1..].find('"').map(|e| (j + 1, j + 1 + e, j + 2 + e)),
+                Some(_) => {
+                    let e = j + src[j..].find([',', '#']).unwrap_or(src.len() - j);
+                    Some((j, e, e))
+                }
+                None => None,
+            };
+            let Some((from, to, next)) = end else { break };
+            value.push_str(&src[from..to]);
+            j = next + src[next..].len() - src[next..].trim_start().len();
+            if bytes.get(j) != Some(&b'#') {
+                break;
+            }
+            j += 1;
+        }
+        if !name.is_empty() {
+            fields.insert(name, tex::collapse_ws(&value));
+        }
+        i = j.min(src.len());
+        match src[i..].find(',') {
+            Some(comma) => i += comma + 1,
+            None => break,
+        }
+    }
+    fields
+}
+
+/// `open`にある括弧に対応する閉じ括弧の位置。
+/// `.bib`では`%`はコメントではなく(`url = {https://x/a%20b}`)、BibTeXは`\`の後の括弧も数える
+fn brace_end(bytes: &[u8], open: usize, left: u8, right: u8) -> Option<usize> {
+    let mut depth = 0usize;
+    for (i, &b) in bytes.iter().enumerate().skip(open) {
+        if b == left {
+            depth += 1;
+        } else if b == right {
+            depth -= 1;
+            if depth == 0 {
+                return Some(i);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_is_not_a_comment() {
+        let entries = parse("@misc{a, url = {https://x/a%20b}, title = {A}}\n@book{b, title = \"B\"}");
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].fields["url"], "https://x/a%20b");
+        assert_eq!(entries[0].fields["title"], "A");
+        assert_eq!(entries[1].key, "b");
+    }
+
+    #[test]
+    fn reads_nested_braces_and_concatenation() {
+        let entries = parse("@Article(k,\n  Title = {The {LaTeX} book},\n  note = \"a\" # {b},\n  year = 2020\n)");
+        assert_eq!(entries[0].kind, "article");
+        assert_eq!(entries[0].fields["title"], "The {LaTeX} book");
+        assert_eq!(entries[0].fields["note"], "ab");
+        assert_eq!(entries[0].fields["year"], "2020");
+    }
+
+    #[test]
+    fn skips_strings_and_comments() {
+        let entries = parse("@string{me = {Me}}\n@comment{x}\n@misc{k, author = me}");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].fields["author"], "me");
+    }
+}
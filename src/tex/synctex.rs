@@ -0,0 +1,79 @@
+//! `.synctex.gz`から、ソースの行がどのページに出力されたかを引く。
+//! 位置(座標)までは使わないので、各レコードの`入力番号,行番号`とページだけを読む。
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Read as _,
+    path::{Path, PathBuf},
+};
+
+use flate2::read::GzDecoder;
+
+use crate::{error, tex};
+
+#[derive(Debug, Default)]
+pub struct SyncTex {
+    /// 入力ファイル(正規化した絶対パス)から、行番号ごとの最初のページへ
+    files: HashMap<PathBuf, BTreeMap<usize, usize>>,
+}
+impl SyncTex {
+    /// `path`(`.synctex.gz`)を読む。`base`はSyncTeXに書かれた相対パスの基準(タイプセット時の作業ディレクトリ)
+    pub fn read(path: &Path, base: &Path) -> error::Result<SyncTex> {
+        let raw = error::read(path)?;
+        let mut text = String::new();
+        GzDecoder::new(&raw[..]).read_to_string(&mut text).map_err(|e| error::Error::FileReadFailed { path: path.to_owned(), source: e })?;
+        Ok(SyncTex::parse(&text, base))
+    }
+
+    /// 展開済みのSyncTeXを読む
+    fn parse(text: &str, base: &Path) -> SyncTex {
+        let mut inputs = HashMap::new();
+        let mut files = HashMap::<PathBuf, BTreeMap<usize, usize>>::new();
+        let mut page = 0;
+        for line in text.lines() {
+            if let Some(input) = line.strip_prefix("Input:") {
+                if let Some((tag, file)) = input.split_once(':') {
+                    inputs.insert(tag.to_string(), tex::normalize(&base.join(file)));
+                }
+                continue;
+            }
+            let Some(kind) = line.chars().next() else { continue };
+            let rest = &line[kind.len_utf8()..];
+            match kind {
+                '{' => page = rest.parse().unwrap_or(page),
+                '[' | '(' | 'x' | 'k' | 'g' | '$' | 'h' | 'v' if page > 0 => {
+                    let Some((tag, rest)) = rest.split_once(',') else { continue };
+                    let Some(line_no) = rest.split(|c: char| !c.is_ascii_digit()).next().and_then(|n| n.parse().ok()) else { continue };
+                    let Some(file) = inputs.get(tag.trim()) else { continue };
+                    files.entry(file.clone()).or_default().entry(line_no).or_insert(page);
+                }
+                _ => {}
+            }
+        }
+        SyncTex { files }
+    }
+
+    /// `file`の`line`行目が出力されたページ。その行に何も無ければ、後ろの一番近い行で代用する
+    pub fn page_of(&self, file: &Path, line: usize) -> Option<usize> {
+        let lines = self.files.get(&tex::normalize(file))?;
+        lines.range(line..).next().or_else(|| lines.range(..line).next_back()).map(|(_, &page)| page)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_lines_to_pages() {
+        let text = "SyncTeX Version:1\nInput:1:./main.tex\nInput:2:./sec/../chap.tex\nContent:\n{1\n[1,3:0,0:1,1,1\nh1,5(-1):2,3\n}1\n{2\nx2,10:4,5\nk1,20:1,1\n}2\n";
+        let base = Path::new("/doc");
+        let synctex = SyncTex::parse(text, base);
+        assert_eq!(synctex.page_of(&base.join("main.tex"), 5), Some(1));
+        // 何も無い行は後ろの近い行で、最後より後ろなら最後の行で代用する
+        assert_eq!(synctex.page_of(&base.join("main.tex"), 6), Some(2));
+        assert_eq!(synctex.page_of(&base.join("main.tex"), 99), Some(2));
+        assert_eq!(synctex.page_of(&base.join("chap.tex"), 1), Some(2));
+        assert_eq!(synctex.page_of(&base.join("other.tex"), 1), None);
+    }
+}
@@ -0,0 +1,116 @@
+pub mod git;
+pub mod hg;
+pub mod jj;
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{
+    error::{self, Error},
+    param::Param,
+};
+
+/// 比較元を取り出すのに使うバージョン管理システム。
+/// パスは特に断りが無い限りリポジトリのルートからの相対パス、`id`は`resolve`で得たもの。
+pub trait Backend: Sync {
+    /// リビジョンを、内容が変わらない識別子(コミットハッシュなど)に解決する
+    fn resolve(&self, rev: &str) -> error::Result<String>;
+    /// リビジョン時点のファイルの中身
+    fn show(&self, id: &str, path: &Path) -> error::Result<Vec<u8>>;
+    /// リビジョンに含まれる全ファイル
+    fn ls_files(&self, id: &str) -> error::Result<Vec<PathBuf>>;
+    /// リビジョンから作業コピーまでに変わったファイル
+    fn changed_files(&self, id: &str) -> error::Result<Vec<PathBuf>>;
+    /// リビジョンの説明の1行目
+    fn describe(&self, id: &str) -> error::Result<String>;
+    /// リポジトリのルートから文書ディレクトリへの相対パス
+    fn prefix(&self) -> error::Result<PathBuf>;
+    /// リビジョンのツリー全体を`dest`に展開する。作業コピーには触らない
+    fn extract(&self, id: &str, dest: &Path) -> error::Result<()>;
+}
+
+/// 指定されたバージョン管理システムのうち、こちらで読めるもの。gitのリポジトリでなければ`None`
+pub fn backend(param: &Param) -> error::Result<Option<Box<dyn Backend + '_>>> {
+    let vc = &param.latexdiffvc_opts;
+    let dir = param.dir.as_path();
+    if vc.svn || vc.cvs || vc.rcs {
+        return Ok(None);
+    }
+    let backend: Box<dyn Backend> = if vc.hg {
+        Box::new(hg::Hg { dir })
+    } else if vc.jj {
+        Box::new(jj::Jj::new(dir))
+    } else {
+        let git = git::Git { dir };
+        if !git.is_repo() {
+            return Ok(None);
+        }
+        Box::new(git)
+    };
+    // hgやjjを指定されたのにリポジトリでなければ(コマンドが無ければ)ここで分かる
+    backend.prefix()?;
+    Ok(Some(backend))
+}
+
+/// コマンドを実行して標準出力を返す。失敗すれば標準エラー出力をエラーにする
+fn run(mut cmd: Command) -> error::Result<Vec<u8>> {
+    let output = cmd.output().map_err(Error::CommandFailed)?;
+    if !output.status.success() {
+        return Err(Error::StdErr(output.stderr));
+    }
+    Ok(output.stdout)
+}
+
+fn lines(out: &[u8]) -> Vec<PathBuf> {
+    String::from_utf8_lossy(out).lines().filter(|l| !l.is_empty()).map(PathBuf::from).collect()
+}
+
+/// ルートの絶対パスから文書ディレクトリへの相対パスを求める
+fn prefix_from_root(dir: &Path, root: &Path) -> error::Result<PathBuf> {
+    let (dir, root) = (error::canonicalize(dir)?, error::canonicalize(root)?);
+    Ok(dir.strip_prefix(&root).map_or_else(|_| PathBuf::new(), Path::to_path_buf))
+}
+
+/// テスト用に`tool`のリポジトリを一時ディレクトリの`repo`に作り、`sub/doc.tex`を置いて`setup`を順に実行する。
+/// gitのほかは入っていないこともあるので、それらのテストは`#[ignore]`にして`cargo test -- --ignored`で動かす
+#[cfg(test)]
+fn scratch_repo(tool: &str, setup: &[&[&str]]) -> crate::testing::TempDir {
+    assert!(Command::new(tool).arg("--version").output().is_ok(), "{tool} not found");
+    let tmp = crate::testing::TempDir::new(tool);
+    let root = tmp.join("repo");
+    std::fs::create_dir_all(root.join("sub")).unwrap();
+    std::fs::write(root.join("sub/doc.tex"), "old\n").unwrap();
+    for args in setup {
+        let output = Command::new(tool).args(*args).current_dir(&root).env("HGPLAIN", "1").output().unwrap();
+        assert!(output.status.success(), "{tool} {args:?}: {}", String::from_utf8_lossy(&output.stderr));
+    }
+    tmp
+}
+
+/// `scratch_repo`で作ったリポジトリの`sub`を文書ディレクトリとして、`rev`の`resolve`、`prefix`、`show`、`extract`を確かめる
+#[cfg(test)]
+fn check_backend(tmp: &Path, backend: &dyn Backend, rev: &str) {
+    let id = backend.resolve(rev).unwrap();
+    assert!(!id.is_empty() && id.bytes().all(|b| b.is_ascii_hexdigit()), "{id}");
+    assert_eq!(backend.prefix().unwrap(), Path::new("sub"));
+    // 作業コピーを変えても、取り出すのはリビジョンの内容
+    std::fs::write(tmp.join("repo/sub/doc.tex"), "new\n").unwrap();
+    assert_eq!(backend.show(&id, Path::new("sub/doc.tex")).unwrap(), b"old\n");
+    let dest = tmp.join("extracted");
+    backend.extract(&id, &dest).unwrap();
+    assert_eq!(std::fs::read(dest.join("sub/doc.tex")).unwrap(), b"old\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn git_backend() {
+        let commit = ["-c", "user.name=t", "-c", "user.email=t@example.com", "-c", "commit.gpgsign=false", "commit", "-qm", "init"];
+        let tmp = scratch_repo("git", &[&["init", "-q"], &["add", "."], &commit]);
+        check_backend(&tmp, &git::Git { dir: &tmp.join("repo/sub") }, "HEAD");
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use super::Backend;
+use crate::error::{self, Error};
+
+/// `git blame`の一行分
+#[derive(Debug, Clone, Default)]
+pub struct BlameLine {
+    /// まだコミットされていない行は`0`だけのハッシュになる
+    pub hash: String,
+    pub author: String,
+    pub time: i64,
+    pub summary: String,
+}
+impl BlameLine {
+    pub fn is_committed(&self) -> bool {
+        self.hash.bytes().any(|b| b != b'0')
+    }
+}
+
+// 文書ディレクトリでgitを叩く。パスは特に断りが無い限りリポジトリのルートからの相対パス。
+pub struct Git<'a> {
+    pub dir: &'a Path,
+}
+impl Git<'_> {
+    pub fn command(&self) -> Command {
+        let mut cmd = Command::new("git");
+        cmd.current_dir(self.dir);
+        cmd
+    }
+    pub fn output<I: IntoIterator<Item = S>, S: AsRef<OsStr>>(&self, args: I) -> error::Result<Vec<u8>> {
+        let output = self.command().args(args).output().map_err(Error::CommandFailed)?;
+        if !output.status.success() {
+            return Err(Error::StdErr(output.stderr));
+        }
+        Ok(output.stdout)
+    }
+    fn output_line<I: IntoIterator<Item = S>, S: AsRef<OsStr>>(&self, args: I) -> error::Result<String> {
+        Ok(String::from_utf8_lossy(&self.output(args)?).trim_end().to_string())
+    }
+
+    /// 文書ディレクトリがgitの管理下にあるか
+    pub fn is_repo(&self) -> bool {
+        self.command().args(["rev-parse", "--git-dir"]).output().is_ok_and(|o| o.status.success())
+    }
+    /// リビジョンをコミットハッシュに解決する
+    pub fn rev_parse(&self, rev: &str) -> error::Result<String> {
+        self.output_line(["rev-parse", "--verify", &format!("{rev}^{{commit}}")])
+    }
+    /// 範囲(`v1..HEAD`など)のコミットを古い順に。マージで入ったコミットは辿らない
+    pub fn rev_list(&self, range: &str) -> error::Result<Vec<String>> {
+        let out = self.output(["rev-list", "--reverse", "--first-parent", range])?;
+        Ok(String::from_utf8_lossy(&out).lines().map(str::to_string).collect())
+    }
+    /// インデックスの内容を木オブジェクトとして書き、そのハッシュを返す。コミットと同じように`show`や`extract`に使える
+    pub fn write_tree(&self) -> error::Result<String> {
+        self.output_line(["write-tree"])
+    }
+    /// パターンに合うタグを作成日時の古い順に
+    pub fn tags(&self, pattern: &str) -> error::Result<Vec<String>> {
+        let out = self.output(["tag", "--list", "--sort=creatordate", pattern])?;
+        Ok(String::from_utf8_lossy(&out).lines().map(str::to_string).collect())
+    }
+    /// 二つのリビジョンの共通の祖先
+    pub fn merge_base(&self, a: &str, b: &str) -> error::Result<String> {
+        self.output_line(["merge-base", a, b])
+    }
+    /// リポジトリのルート(絶対パス)
+    pub fn toplevel(&self) -> error::Result<PathBuf> {
+        Ok(self.output_line(["rev-parse", "--show-toplevel"])?.into())
+    }
+    /// 作業ツリーの`path`(文書ディレクトリからの相対パス)の`lo`行目から`hi`行目までを、最後に変更したコミット
+    pub fn blame(&self, path: &Path, lo: usize, hi: usize) -> error::Result<Vec<BlameLine>> {
+        let out = self.output([OsStr::new("blame"), OsStr::new("--porcelain"), OsStr::new("-L"), OsStr::new(&format!("{lo},{hi}")), OsStr::new("--"), path.as_os_str()])?;
+        Ok(parse_blame(&String::from_utf8_lossy(&out)))
+    }
+    /// 作業ツリーの`path`(文書ディレクトリからの相対パス)がまだ`git add`されていないか。リポジトリの外なら`false`
+    pub fn is_untracked(&self, path: &Path) -> bool {
+        let args = [OsStr::new("ls-files"), OsStr::new("--others"), OsStr::new("--"), path.as_os_str()];
+        self.output(args).is_ok_and(|out| !out.trim_ascii().is_empty())
+    }
+}
+
+impl Backend for Git<'_> {
+    fn resolve(&self, rev: &str) -> error::Result<String> {
+        self.rev_parse(rev)
+    }
+    fn show(&self, id: &str, path: &Path) -> error::Result<Vec<u8>> {
+        // `rev:path`はルートからのパスとして解釈される(`./`を付けない限り)。区切りは常に`/`
+        let spec = format!("{id}:{}", path.to_string_lossy().replace('\\', "/"));
+        self.output(["show", &spec])
+    }
+    fn ls_files(&self, id: &str) -> error::Result<Vec<PathBuf>> {
+        let out = self.output(["ls-tree", "-r", "--name-only", "--full-tree", "-z", id])?;
+        Ok(out.split(|&b| b == 0).filter(|p| !p.is_empty()).map(|p| String::from_utf8_lossy(p).into_owned().into()).collect())
+    }
+    fn changed_files(&self, id: &str) -> error::Result<Vec<PathBuf>> {
+        let out = self.output(["diff", "--name-only", "-z", id, "--"])?;
+        Ok(out.split(|&b| b == 0).filter(|p| !p.is_empty()).map(|p| String::from_utf8_lossy(p).into_owned().into()).collect())
+    }
+    fn describe(&self, id: &str) -> error::Result<String> {
+        self.output_line(["log", "-1", "--format=%s", id])
+    }
+    fn prefix(&self) -> error::Result<PathBuf> {
+        Ok(self.output_line(["rev-parse", "--show-prefix"])?.into())
+    }
+    fn extract(&self, id: &str, dest: &Path) -> error::Result<()> {
+        // サブディレクトリで実行するとそのディレクトリしかアーカイブされないので、ルートで実行する
+        let mut git = self.command();
+        git.current_dir(self.toplevel()?);
+        archive(git, id, dest)
+    }
+}
+
+/// `git`(リポジトリを指定済みのもの)で`id`のツリー全体を`git archive`し、`dest`に展開する
+pub(super) fn archive(mut git: Command, id: &str, dest: &Path) -> error::Result<()> {
+    git.args(["archive", "--format=tar", id]);
+    let mut child = git.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().map_err(Error::CommandFailed)?;
+    let stdout = child.stdout.take().expect("handle present");
+    let unpacked = tar::Archive::new(stdout).unpack(dest);
+    let output = child.wait_with_output().map_err(Error::CommandFailed)?;
+    if !output.status.success() {
+        return Err(Error::StdErr(output.stderr));
+    }
+    unpacked.map_err(|e| Error::FileWriteFailed { path: dest.to_owned(), source: e })
+}
+
+/// `git blame --porcelain`の出力を行ごとに読む
+fn parse_blame(out: &str) -> Vec<BlameLine> {
+    // コミットの情報はそのコミットが最初に出てきたときだけ書かれる
+    let mut commits = HashMap::<String, BlameLine>::new();
+    let mut lines = Vec::new();
+    let mut current = String::new();
+    for line in out.lines() {
+        if line.starts_with('\t') {
+            lines.push(commits.get(&current).cloned().unwrap_or_default());
+        } else if let Some((head, _)) = line.split_once(' ')
+            && head.len() == 40
+            && head.bytes().all(|b| b.is_ascii_hexdigit())
+        {
+            current = head.to_string();
+            commits.entry(current.clone()).or_insert_with(|| BlameLine { hash: current.clone(), ..Default::default() });
+        } else if let Some(commit) = commits.get_mut(&current) {
+            match line.split_once(' ') {
+                Some(("author", author)) => commit.author = author.to_string(),
+                Some(("author-time", time)) => commit.time = time.parse().unwrap_or(0),
+                Some(("summary", summary)) => commit.summary = summary.to_string(),
+                _ => {}
+            }
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_porcelain_blame() {
+        let old = "1234567890abcdef1234567890abcdef12345678";
+        let out = format!(
+            "{old} 3 3 2\nauthor Alice\nauthor-time 1700000000\nsummary Add intro\nfilename chap1.tex\n\tfirst\n\
+             {old} 4 4\n\tsecond\n\
+             {zero} 5 5 1\nauthor Not Committed Yet\nauthor-time 1800000000\nsummary Version of chap1.tex from chap1.tex\nfilename chap1.tex\n\tthird\n",
+            zero = "0".repeat(40)
+        );
+        let lines = parse_blame(&out);
+        assert_eq!(lines.len(), 3);
+        assert_eq!((lines[1].hash.as_str(), lines[1].author.as_str(), lines[1].time, lines[1].summary.as_str()), (old, "Alice", 1700000000, "Add intro"));
+        assert!(lines[0].is_committed() && !lines[2].is_committed());
+    }
+}
@@ -0,0 +1,73 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use super::{Backend, lines, prefix_from_root, run};
+use crate::error;
+
+// 文書ディレクトリでhgを叩く。ユーザーの設定で出力が変わらないよう`HGPLAIN`を付ける
+pub struct Hg<'a> {
+    pub dir: &'a Path,
+}
+impl Hg<'_> {
+    fn command(&self) -> Command {
+        let mut cmd = Command::new("hg");
+        cmd.current_dir(self.dir).env("HGPLAIN", "1");
+        cmd
+    }
+    fn output_line(&self, args: &[&str]) -> error::Result<String> {
+        let mut cmd = self.command();
+        cmd.args(args);
+        Ok(String::from_utf8_lossy(&run(cmd)?).trim_end().to_string())
+    }
+    /// パスをルートからのものとして出させるため、ルートで実行する
+    fn at_root(&self) -> error::Result<Command> {
+        let mut cmd = self.command();
+        cmd.current_dir(self.output_line(&["root"])?);
+        Ok(cmd)
+    }
+}
+impl Backend for Hg<'_> {
+    fn resolve(&self, rev: &str) -> error::Result<String> {
+        self.output_line(&["log", "--limit", "1", "--rev", rev, "--template", "{node}"])
+    }
+    fn show(&self, id: &str, path: &Path) -> error::Result<Vec<u8>> {
+        let mut cmd = self.at_root()?;
+        cmd.args(["cat", "--rev", id]).arg(format!("path:{}", path.to_string_lossy().replace('\\', "/")));
+        run(cmd)
+    }
+    fn ls_files(&self, id: &str) -> error::Result<Vec<PathBuf>> {
+        let mut cmd = self.at_root()?;
+        cmd.args(["files", "--rev", id]);
+        Ok(lines(&run(cmd)?))
+    }
+    fn changed_files(&self, id: &str) -> error::Result<Vec<PathBuf>> {
+        let mut cmd = self.at_root()?;
+        cmd.args(["status", "--rev", id, "--modified", "--added", "--removed", "--no-status"]);
+        Ok(lines(&run(cmd)?))
+    }
+    fn describe(&self, id: &str) -> error::Result<String> {
+        self.output_line(&["log", "--limit", "1", "--rev", id, "--template", "{desc|firstline}"])
+    }
+    fn prefix(&self) -> error::Result<PathBuf> {
+        prefix_from_root(self.dir, Path::new(&self.output_line(&["root"])?))
+    }
+    fn extract(&self, id: &str, dest: &Path) -> error::Result<()> {
+        let mut cmd = self.at_root()?;
+        cmd.args(["archive", "--rev", id, "--type", "files", "--no-decode"]).arg(dest);
+        run(cmd).map(drop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[ignore = "needs hg"]
+    fn hg_backend() {
+        let tmp = super::super::scratch_repo("hg", &[&["init"], &["add"], &["commit", "-m", "init", "-u", "t"]]);
+        super::super::check_backend(&tmp, &Hg { dir: &tmp.join("repo/sub") }, ".");
+    }
+}
@@ -0,0 +1,112 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+    sync::OnceLock,
+};
+
+use super::{Backend, git, lines, prefix_from_root, run};
+use crate::error;
+
+// 文書ディレクトリでjjを叩く。読むだけなので作業コピーのスナップショットは取らせない
+// (並行して実行してもロックを取り合わないように)
+pub struct Jj<'a> {
+    dir: &'a Path,
+    /// `jj root`の結果。ファイルごとに聞き直さないよう一度だけ調べる
+    root: OnceLock<PathBuf>,
+}
+impl<'a> Jj<'a> {
+    pub fn new(dir: &'a Path) -> Jj<'a> {
+        Jj { dir, root: OnceLock::new() }
+    }
+    fn root(&self) -> error::Result<&Path> {
+        if let Some(root) = self.root.get() {
+            return Ok(root);
+        }
+        let root = PathBuf::from(self.output_line(&["root"])?);
+        Ok(self.root.get_or_init(|| root))
+    }
+    /// 既定のgitを使うリポジトリなら、その(ベアの)gitのディレクトリ
+    fn git_dir(&self) -> error::Result<Option<PathBuf>> {
+        let mut repo = self.root()?.join(".jj").join("repo");
+        // `jj workspace add`で作ったものでは、`repo`は元のリポジトリへのパスを書いたファイル
+        if let Ok(path) = std::fs::read_to_string(&repo) {
+            repo = self.root()?.join(".jj").join(path.trim());
+        }
+        let store = repo.join("store");
+        Ok(std::fs::read_to_string(store.join("git_target")).ok().map(|target| store.join(target.trim())))
+    }
+    fn command(&self) -> Command {
+        let mut cmd = Command::new("jj");
+        cmd.current_dir(self.dir).args(["--ignore-working-copy", "--color=never", "--no-pager"]);
+        cmd
+    }
+    fn output_line(&self, args: &[&str]) -> error::Result<String> {
+        let mut cmd = self.command();
+        cmd.args(args);
+        Ok(String::from_utf8_lossy(&run(cmd)?).trim_end().to_string())
+    }
+    /// パスをルートからのものとして出させるため、ルートで実行する
+    fn at_root(&self) -> error::Result<Command> {
+        let mut cmd = self.command();
+        cmd.current_dir(self.root()?);
+        Ok(cmd)
+    }
+}
+impl Backend for Jj<'_> {
+    fn resolve(&self, rev: &str) -> error::Result<String> {
+        // `@`や変更IDもコミットIDにしておく(変更IDの指す内容は書き換わる)
+        self.output_line(&["log", "--no-graph", "--limit", "1", "-r", rev, "-T", "commit_id"])
+    }
+    fn show(&self, id: &str, path: &Path) -> error::Result<Vec<u8>> {
+        let mut cmd = self.at_root()?;
+        cmd.args(["file", "show", "-r", id]).arg(format!("root:{:?}", path.to_string_lossy().replace('\\', "/")));
+        run(cmd)
+    }
+    fn ls_files(&self, id: &str) -> error::Result<Vec<PathBuf>> {
+        let mut cmd = self.at_root()?;
+        cmd.args(["file", "list", "-r", id]);
+        Ok(lines(&run(cmd)?))
+    }
+    fn changed_files(&self, id: &str) -> error::Result<Vec<PathBuf>> {
+        // ここだけは作業コピーの今の状態が要る
+        let mut cmd = Command::new("jj");
+        cmd.current_dir(self.root()?).args(["--color=never", "--no-pager", "diff", "--name-only", "--from", id, "--to", "@"]);
+        Ok(lines(&run(cmd)?))
+    }
+    fn describe(&self, id: &str) -> error::Result<String> {
+        self.output_line(&["log", "--no-graph", "--limit", "1", "-r", id, "-T", "description.first_line()"])
+    }
+    fn prefix(&self) -> error::Result<PathBuf> {
+        prefix_from_root(self.dir, self.root()?)
+    }
+    fn extract(&self, id: &str, dest: &Path) -> error::Result<()> {
+        // jjにはアーカイブが無い。中身がgitにあれば(コミットIDはgitのものと同じ)、gitで一度に取り出す
+        if let Some(git_dir) = self.git_dir()? {
+            let mut git = Command::new("git");
+            git.arg("--git-dir").arg(git_dir);
+            return git::archive(git, id, dest);
+        }
+        // そうでなければ一つずつ書き出す
+        for path in self.ls_files(id)? {
+            let file = dest.join(&path);
+            error::create_dir_all(file.parent().unwrap_or(dest))?;
+            error::write(&file, self.show(id, &path)?)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[ignore = "needs jj"]
+    fn jj_backend() {
+        let commit = ["--config", "user.name=t", "--config", "user.email=t@example.com", "commit", "-m", "init"];
+        let tmp = super::super::scratch_repo("jj", &[&["git", "init"], &commit]);
+        let sub = tmp.join("repo/sub");
+        let jj = Jj::new(&sub);
+        super::super::check_backend(&tmp, &jj, "@-");
+    }
+}
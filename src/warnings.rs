@@ -0,0 +1,182 @@
+//! 古い版と新しい版のビルドの`.log`を比べ、新しく出た警告を報告する。
+//! 行番号やページは版ごとに変わるので、警告は対象(ラベル名や箱の中身など)で突き合わせる。
+
+use std::{collections::HashSet, path::Path};
+
+use crate::{baseline::Baseline, error, osstr_join, param::Param};
+
+/// TeXは`.log`をこの長さで折り返す(環境変数`max_print_line`で変えられる)
+const MAX_PRINT_LINE: usize = 79;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Kind {
+    UndefinedReference,
+    UndefinedCitation,
+    MultiplyDefined,
+    Overfull,
+    Font,
+}
+impl Kind {
+    fn title(self) -> &'static str {
+        match self {
+            Kind::UndefinedReference => "undefined references",
+            Kind::UndefinedCitation => "undefined citations",
+            Kind::MultiplyDefined => "multiply-defined labels",
+            Kind::Overfull => "overfull boxes",
+            Kind::Font => "font warnings",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Warning {
+    pub kind: Kind,
+    /// 版をまたいで同じ警告かを判断するためのもの
+    pub key: String,
+    /// `.log`に出ていたそのままの文
+    pub message: String,
+}
+
+/// 折り返された行を繋ぐ。pdfTeXはバイト数で、XeTeXとLuaTeXは文字数で折り返す
+fn join_wrapped(log: &str, width: usize) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut wrapped = false;
+    for line in log.lines() {
+        match lines.last_mut() {
+            Some(last) if wrapped => last.push_str(line),
+            _ => lines.push(line.to_string()),
+        }
+        wrapped = line.len() == width || line.chars().count() == width;
+    }
+    lines
+}
+
+/// `.log`から警告を拾う
+pub fn parse_log(log: &str) -> Vec<Warning> {
+    let width = std::env::var("max_print_line").ok().and_then(|w| w.trim().parse().ok()).unwrap_or(MAX_PRINT_LINE);
+    let lines = join_wrapped(log, width);
+
+    let mut warnings = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        // 続きの行(`(Font)  ...`や`(natbib) ...`)も一つの文にする
+        let continued = || {
+            let rest = lines[i + 1..].iter().take_while(|l| l.starts_with('(') && l.contains(")  "));
+            let parts = rest.map(|l| l.split_once(')').map_or("", |(_, text)| text.trim()));
+            std::iter::once(line.trim()).chain(parts).collect::<Vec<_>>().join(" ")
+        };
+        let warning = if let Some(rest) = line.strip_prefix("LaTeX Font Warning: ") {
+            let message = continued();
+            let key = strip_location(&rest.replace("(Font)", ""));
+            Some(Warning { kind: Kind::Font, key, message })
+        } else if line.starts_with("LaTeX Warning: ") || line.starts_with("Package natbib Warning: ") {
+            let message = continued();
+            let quoted = quoted(&message).map(str::to_string);
+            let kind = if message.contains("Reference `") && message.contains("undefined") {
+                Some(Kind::UndefinedReference)
+            } else if message.contains("Citation `") && message.contains("undefined") {
+                Some(Kind::UndefinedCitation)
+            } else if message.contains("Label `") && message.contains("multiply defined") {
+                Some(Kind::MultiplyDefined)
+            } else {
+                None
+            };
+            kind.zip(quoted).map(|(kind, key)| Warning { kind, key, message })
+        } else if line.starts_with("Overfull \\hbox") || line.starts_with("Overfull \\vbox") {
+            // 箱の中身が次の行に出るので、それで突き合わせる
+            let content = lines.get(i + 1).map_or("", |l| l.trim());
+            Some(Warning { kind: Kind::Overfull, key: content.to_string(), message: line.trim().to_string() })
+        } else {
+            None
+        };
+        warnings.extend(warning);
+    }
+    warnings
+}
+
+/// `` `name' ``の`name`
+fn quoted(message: &str) -> Option<&str> {
+    let (_, rest) = message.split_once('`')?;
+    rest.split_once('\'').map(|(name, _)| name)
+}
+
+/// `on input line 12`や`on page 3`を取り除く
+fn strip_location(message: &str) -> String {
+    let words: Vec<&str> = message.split_whitespace().collect();
+    let mut kept = Vec::new();
+    let mut i = 0;
+    while i < words.len() {
+        match words[i..] {
+            ["on", "input", "line", ..] => i += 4,
+            ["on", "page", ..] => i += 3,
+            _ => {
+                kept.push(words[i]);
+                i += 1;
+            }
+        }
+    }
+    kept.join(" ")
+}
+
+fn read_log(path: &Path) -> Vec<Warning> {
+    std::fs::read(path).map_or_else(|_| Vec::new(), |log| parse_log(&String::from_utf8_lossy(&log)))
+}
+
+/// 古い版のビルドには無かった警告を種類ごとに表示する
+pub fn report(param: &Param) -> error::Result<()> {
+    let Some(baseline) = Baseline::from_param(param)? else { return Ok(()) };
+    if baseline.build()? != Some(0) {
+        return Err(error::Error::AlreadySaid);
+    }
+    let log_name = osstr_join(&param.docfile, ".log");
+    let old: HashSet<(Kind, String)> = read_log(&baseline.build_dir().join(&log_name)).into_iter().map(|w| (w.kind, w.key)).collect();
+    let mut new = read_log(&param.tmpdir.join(&log_name));
+    new.retain(|w| !old.contains(&(w.kind, w.key.clone())));
+    new.sort_by_key(|w| w.kind);
+
+    println!("New warnings introduced since {}:", param.old_revision());
+    if new.is_empty() {
+        println!("  none");
+    }
+    let mut seen = HashSet::new();
+    for (n, warning) in new.iter().enumerate() {
+        if n == 0 || new[n - 1].kind != warning.kind {
+            println!("  {}:", warning.kind.title());
+        }
+        // 同じ警告が何度も出ることがあるので一度だけ表示する
+        if seen.insert((warning.kind, &warning.message)) {
+            println!("    {}", warning.message);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn joins_lines_wrapped_by_bytes_or_characters() {
+        let ascii = "a".repeat(79);
+        // XeTeXは文字数で数えるので、日本語を含む行はバイト数では79にならない
+        let utf8 = format!("{}{}", "あ".repeat(10), "a".repeat(69));
+        let log = format!("{ascii}\nb\n{utf8}\nc\nd\n");
+        assert_eq!(join_wrapped(&log, 79), vec![format!("{ascii}b"), format!("{utf8}c"), "d".to_string()]);
+    }
+
+    #[test]
+    fn matches_warnings_by_target() {
+        let log = "LaTeX Warning: Reference `sec:intro' on page 2 undefined on input line 12.\n\
+            LaTeX Font Warning: Font shape `OT1/cmr/bx/sc' undefined\n(Font)              using `OT1/cmr/bx/n' instead on input line 5.\n\
+            Overfull \\hbox (1.0pt too wide) in paragraph at lines 3--4\n[]\\OT1/cmr/m/n/10 long word\n";
+        let warnings = parse_log(log);
+        let keys: Vec<(Kind, &str)> = warnings.iter().map(|w| (w.kind, w.key.as_str())).collect();
+        assert_eq!(
+            keys,
+            vec![
+                (Kind::UndefinedReference, "sec:intro"),
+                (Kind::Font, "Font shape `OT1/cmr/bx/sc' undefined"),
+                (Kind::Overfull, "[]\\OT1/cmr/m/n/10 long word"),
+            ]
+        );
+    }
+}